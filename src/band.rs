@@ -0,0 +1,83 @@
+// A band of whole screen columns that gets drawn on its own, so bands can be drawn
+// on different threads. Pixels are RGBA, stored row by row like the screen itself.
+// Coordinates are relative to the viewport the band is part of, which is only
+// placed on the screen when the band is read or written.
+pub struct Band {
+    pixels: Vec<u8>,
+    // Screen column of the band's first column
+    left: i32,
+    width: i32,
+    height: i32,
+}
+
+impl Band {
+    pub fn new(left: i32, width: i32, height: i32) -> Band {
+        Band {
+            pixels: vec![0; (width * height * 4) as usize],
+            left,
+            width,
+            height,
+        }
+    }
+
+    // Splits a screen into `count` bands about as wide as each other
+    pub fn split(screen_width: i32, screen_height: i32, count: usize) -> Vec<Band> {
+        let count = (count as i32).max(1).min(screen_width.max(1));
+        let width = (screen_width + count - 1) / count;
+
+        (0..count)
+            .map(|i| i * width)
+            .take_while(|left| *left < screen_width)
+            .map(|left| Band::new(left, width.min(screen_width - left), screen_height))
+            .collect()
+    }
+
+    pub fn left(&self) -> i32 {
+        self.left
+    }
+
+    pub fn right(&self) -> i32 {
+        self.left + self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    // The RGBA of the pixel at screen position (x, y), if it's in the band at all
+    pub fn pixel_mut(&mut self, x: i32, y: i32) -> Option<&mut [u8]> {
+        let x = x - self.left;
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return None;
+        }
+        let idx = ((self.width * y + x) * 4) as usize;
+        Some(&mut self.pixels[idx..idx + 4])
+    }
+
+    // Starts from whatever is on the screen already. `origin` is where the
+    // viewport's top left corner is on the screen.
+    pub fn read_from(&mut self, screen: &[u8], screen_width: i32, origin: (i32, i32)) {
+        for (y, row) in self.rows().enumerate() {
+            let start = self.screen_offset(screen_width, origin, y as i32);
+            self.pixels[row.clone()].copy_from_slice(&screen[start..start + row.len()]);
+        }
+    }
+
+    pub fn write_to(&self, screen: &mut [u8], screen_width: i32, origin: (i32, i32)) {
+        for (y, row) in self.rows().enumerate() {
+            let start = self.screen_offset(screen_width, origin, y as i32);
+            screen[start..start + row.len()].copy_from_slice(&self.pixels[row]);
+        }
+    }
+
+    // Byte offset in the screen of the band's first pixel on a row
+    fn screen_offset(&self, screen_width: i32, (x, y): (i32, i32), row: i32) -> usize {
+        ((screen_width * (y + row) + x + self.left) * 4) as usize
+    }
+
+    // Byte ranges of each row of the band
+    fn rows(&self) -> impl Iterator<Item = std::ops::Range<usize>> {
+        let stride = (self.width * 4) as usize;
+        (0..self.height as usize).map(move |y| y * stride..(y + 1) * stride)
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::{Duration, Instant};
+
+use crate::camera::{Camera, Viewport};
+use crate::fixed::Trig;
+use crate::font::Font;
+use crate::game_plugin::{Position, Rotation};
+use crate::light::LightSource;
+use crate::profile::{Phase, PhaseTimes};
+use crate::raycaster::{raycast, Map};
+use crate::rays::RayTable;
+use crate::settings::RenderSettings;
+use crate::texture::Texture;
+use crate::{MONITOR_SIZE, TILE_SIZE};
+
+// How long the camera takes to glide from one tile to the next
+const FRAMES_PER_TILE: usize = 8;
+// Degrees the camera turns every frame while it moves
+const TURN_PER_FRAME: f32 = 3.0;
+
+// Renders a scripted flythrough of a map without opening a window, and
+// prints how long frames took, per phase
+pub fn run(
+    map_path: &str,
+    frames: usize,
+    settings: RenderSettings,
+    fov: f32,
+) -> Result<(), String> {
+    let mut map = Map::load(map_path)?;
+    let (mut walls, floor_texture, colormap) = crate::load_textures(map.fog())?;
+    // Nothing draws into the live textures here, they just need to be there
+    for camera in map.cameras() {
+        walls.bind(
+            camera.texture,
+            Texture::blank(MONITOR_SIZE as u32, MONITOR_SIZE as u32),
+        );
+    }
+    map.check_wall_textures(walls.tiles().len())?;
+    let trig = Trig::new();
+    let camera = Camera::new(fov, Viewport::new(0, 0, 320, 200));
+    let rays = RayTable::new(320, fov, settings.spacing, settings.math, &trig);
+
+    // Text doesn't need a window, just the ttf library
+    let fonts = Font::new(sdl2::ttf::init().map_err(|e| e.to_string())?);
+    let text = fonts.build("assets/font.ttf", 18)?;
+
+    let path = flythrough(&map);
+    if path.is_empty() {
+        return Err(format!("{} has nowhere to stand", map_path));
+    }
+
+    let torch = LightSource::torch();
+    let mut pixels = vec![0; 320 * 200 * 4];
+    let mut stats = Vec::with_capacity(frames);
+
+    for frame in 0..frames {
+        let (position, rotation) = camera_at(&path, frame);
+        let start = Instant::now();
+        let mut times = PhaseTimes::default();
+
+        pixels.copy_from_slice(&[0, 0, 0, 0xff].repeat(320 * 200));
+
+        times.time(Phase::Lighting, || {
+            map.animate_lights(frame as f64 / 60.0);
+            map.set_dynamic_lights(&[torch.at(position.x, position.y)]);
+        });
+
+        let render_times = raycast(
+            (320, 200),
+            &camera,
+            &rays,
+            &position,
+            &rotation,
+            &mut pixels,
+            &walls,
+            &floor_texture,
+            &map,
+            &settings,
+            &colormap,
+            &trig,
+        )?;
+        for phase in &[Phase::Walls, Phase::Floors] {
+            times.add(*phase, render_times.get(*phase));
+        }
+
+        times.time(Phase::Text, || {
+            text.draw(&format!("{}", frame), &mut pixels)
+        })?;
+
+        stats.push((start.elapsed(), times));
+    }
+
+    println!(
+        "{} frames of {} on {} threads",
+        frames,
+        map_path,
+        if settings.threads == 0 {
+            rayon::current_num_threads()
+        } else {
+            settings.threads
+        }
+    );
+    println!("{:<10}{:>10}{:>10}{:>10}", "", "min", "avg", "p99");
+    for phase in Phase::ALL.iter() {
+        let times: Vec<Duration> = stats.iter().map(|(_, t)| t.get(*phase)).collect();
+        print_row(phase.name(), times);
+    }
+    print_row("frame", stats.iter().map(|(total, _)| *total).collect());
+
+    Ok(())
+}
+
+fn print_row(name: &str, mut times: Vec<Duration>) {
+    if times.is_empty() {
+        return;
+    }
+
+    times.sort();
+    let total: Duration = times.iter().sum();
+    let avg = total / times.len() as u32;
+    let p99 = times[((times.len() * 99 + 99) / 100).min(times.len()) - 1];
+
+    let ms = |d: Duration| format!("{:.3}ms", d.as_secs_f64() * 1000.0);
+    println!(
+        "{:<10}{:>10}{:>10}{:>10}",
+        name,
+        ms(times[0]),
+        ms(avg),
+        ms(p99)
+    );
+}
+
+// The centers of every open tile, going back and forth along the rows like a snake,
+// so most steps are to a tile right next to the last one
+fn flythrough(map: &Map) -> Vec<(i32, i32)> {
+    let mut path = Vec::new();
+    for y in 0..map.height() {
+        let row: Vec<(i32, i32)> = (0..map.width())
+            .map(|x| (x, y))
+            .filter(|tile| !map.is_blocking_at(*tile))
+            .collect();
+
+        if y % 2 == 0 {
+            path.extend(row);
+        } else {
+            path.extend(row.into_iter().rev());
+        }
+    }
+    path
+}
+
+// Where the camera is on a frame. It glides between neighbouring tiles and
+// jumps to the next one otherwise, so it never goes through a wall.
+fn camera_at(path: &[(i32, i32)], frame: usize) -> (Position, Rotation) {
+    let step = frame / FRAMES_PER_TILE;
+    let (from, to) = (path[step % path.len()], path[(step + 1) % path.len()]);
+
+    let t = if (from.0 - to.0).abs() + (from.1 - to.1).abs() == 1 {
+        (frame % FRAMES_PER_TILE) as f32 / FRAMES_PER_TILE as f32
+    } else {
+        0.0
+    };
+
+    let center = |v: i32| (v as f32 + 0.5) * TILE_SIZE as f32;
+    let position = Position::new(
+        center(from.0) + (center(to.0) - center(from.0)) * t,
+        center(from.1) + (center(to.1) - center(from.1)) * t,
+    );
+
+    (position, Rotation::new(frame as f32 * TURN_PER_FRAME))
+}
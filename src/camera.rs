@@ -0,0 +1,118 @@
+// Field of view cameras start with, in degrees across their viewport
+pub const DEFAULT_FOV: f32 = 66.0;
+// How far up or down a camera can look, in degrees
+pub const MAX_PITCH: f32 = 30.0;
+
+// The part of the screen a camera draws into, in pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Viewport {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Viewport {
+        Viewport {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn fits(&self, (screen_width, screen_height): (i32, i32)) -> bool {
+        self.x >= 0
+            && self.y >= 0
+            && self.width > 0
+            && self.height > 0
+            && self.x + self.width <= screen_width
+            && self.y + self.height <= screen_height
+    }
+}
+
+// How the screen is shared between players
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    // Two players get a half each, left and right
+    SideBySide,
+    // Two players get a half each, top and bottom
+    Stacked,
+}
+
+// Most players that fit on the screen at once
+pub const MAX_PLAYERS: usize = 4;
+
+// Splits the screen between `players` views. Two players get halves, three or four
+// get quarters (with three the last quarter stays empty).
+pub fn split_screen((width, height): (i32, i32), players: usize, layout: Layout) -> Vec<Viewport> {
+    let (columns, rows) = match (players.max(1).min(MAX_PLAYERS), layout) {
+        (1, _) => (1, 1),
+        (2, Layout::SideBySide) => (2, 1),
+        (2, Layout::Stacked) => (1, 2),
+        _ => (2, 2),
+    };
+    let (view_width, view_height) = (width / columns, height / rows);
+
+    (0..players.max(1).min(MAX_PLAYERS) as i32)
+        .map(|i| {
+            Viewport::new(
+                i % columns * view_width,
+                i / columns * view_height,
+                view_width,
+                view_height,
+            )
+        })
+        .collect()
+}
+
+// Where a camera's picture ends up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Screen,
+    // Into the wall texture with this ID, for monitors showing another part of the level
+    Wall(usize),
+}
+
+// Something the world gets drawn from. It looks from the entity's Position along
+// its Rotation, so it can be on the player, a security camera or a cutscene dolly.
+pub struct Camera {
+    // Degrees up (positive) or down from straight ahead. There's no real 3D, the
+    // horizon just moves up and down the screen.
+    pub pitch: f32,
+    pub fov: f32,
+    // How far in it's zoomed, like looking through binoculars
+    pub zoom: f32,
+    pub viewport: Viewport,
+    pub target: Target,
+}
+
+impl Camera {
+    pub fn new(fov: f32, viewport: Viewport) -> Camera {
+        Camera {
+            pitch: 0.0,
+            fov,
+            zoom: 1.0,
+            viewport,
+            target: Target::Screen,
+        }
+    }
+
+    // A camera that draws into a square wall texture, `size` texels across
+    pub fn on_wall(fov: f32, size: i32, id: usize) -> Camera {
+        Camera {
+            target: Target::Wall(id),
+            ..Camera::new(fov, Viewport::new(0, 0, size, size))
+        }
+    }
+
+    // The field of view it actually draws with, zoom and all
+    pub fn zoomed_fov(&self) -> f32 {
+        self.fov / self.zoom
+    }
+
+    pub fn pitch(&self) -> f32 {
+        self.pitch.max(-MAX_PITCH).min(MAX_PITCH)
+    }
+}
@@ -0,0 +1,69 @@
+use sdl2::keyboard::Keycode;
+
+// How a player turns around
+pub enum Look {
+    // Turning with the mouse, and looking up and down with it too
+    Mouse,
+    // Turning with two keys, for when someone else has the mouse
+    Keys { left: Keycode, right: Keycode },
+}
+
+// Which keys move a player around. Everyone shares the one keyboard,
+// so each player gets their own corner of it.
+pub struct Controls {
+    pub forward: Keycode,
+    pub back: Keycode,
+    pub left: Keycode,
+    pub right: Keycode,
+    pub zoom: Keycode,
+    pub look: Look,
+}
+
+impl Controls {
+    // The layout for a player, numbered from 0. Only the first one gets the mouse.
+    pub fn for_player(number: usize) -> Controls {
+        match number {
+            0 => Controls {
+                forward: Keycode::W,
+                back: Keycode::S,
+                left: Keycode::A,
+                right: Keycode::D,
+                zoom: Keycode::Z,
+                look: Look::Mouse,
+            },
+            1 => Controls {
+                forward: Keycode::Up,
+                back: Keycode::Down,
+                left: Keycode::Comma,
+                right: Keycode::Period,
+                zoom: Keycode::RShift,
+                look: Look::Keys {
+                    left: Keycode::Left,
+                    right: Keycode::Right,
+                },
+            },
+            2 => Controls {
+                forward: Keycode::I,
+                back: Keycode::K,
+                left: Keycode::J,
+                right: Keycode::L,
+                zoom: Keycode::H,
+                look: Look::Keys {
+                    left: Keycode::U,
+                    right: Keycode::O,
+                },
+            },
+            _ => Controls {
+                forward: Keycode::Kp8,
+                back: Keycode::Kp5,
+                left: Keycode::Kp4,
+                right: Keycode::Kp6,
+                zoom: Keycode::Kp0,
+                look: Look::Keys {
+                    left: Keycode::Kp7,
+                    right: Keycode::Kp9,
+                },
+            },
+        }
+    }
+}
@@ -0,0 +1,55 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DitherMode {
+    Off,
+    // Fixed 4x4 Bayer pattern tied to the screen, looks the same every frame
+    Ordered,
+    // Random noise, but seeded, so the same view always gets the same noise
+    Noise { seed: u64 },
+}
+
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+// Hands out the offsets used to break up banding in the lighting, per screen pixel.
+// Every pixel's noise comes only from the seed and where the pixel is, so it doesn't
+// matter in which order (or on which thread) pixels get drawn, or which get skipped.
+pub struct Dither {
+    mode: DitherMode,
+}
+
+impl Dither {
+    pub fn new(mode: DitherMode) -> Dither {
+        Dither { mode }
+    }
+
+    // A pair of offsets between -1 and 1 for the pixel at the given screen position
+    pub fn at(&self, x: i32, y: i32) -> (f32, f32) {
+        match self.mode {
+            DitherMode::Off => (0.0, 0.0),
+            DitherMode::Ordered => (bayer(x, y), bayer(x + 2, y + 1)),
+            DitherMode::Noise { seed } => {
+                let bits = hash(seed, x, y);
+                (unit(bits as u32), unit((bits >> 32) as u32))
+            }
+        }
+    }
+}
+
+fn bayer(x: i32, y: i32) -> f32 {
+    let threshold = BAYER[(y & 3) as usize][(x & 3) as usize] as f32;
+    (threshold + 0.5) / 16.0 * 2.0 - 1.0
+}
+
+// Mixes the seed and a pixel's position into 64 random looking bits (splitmix64's finalizer)
+fn hash(seed: u64, x: i32, y: i32) -> u64 {
+    let mut z = seed
+        ^ (x as u32 as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (y as u32 as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// 32 random bits to somewhere between -1 and 1
+fn unit(bits: u32) -> f32 {
+    (bits >> 8) as f32 / (1 << 23) as f32 * 2.0 - 1.0
+}
@@ -0,0 +1,129 @@
+// 16.16 fixed point math and the trig tables to go with it, no libm
+
+pub const FRAC_BITS: u32 = 16;
+pub const ONE: i32 = 1 << FRAC_BITS;
+
+// How many steps a full turn is split into
+pub const ANGLES: i32 = 1 << 16;
+const QUARTER: i32 = ANGLES / 4;
+
+// Tangents are clamped to this, so rays that are almost straight don't overflow
+const MAX_TAN: i64 = 4096 * ONE as i64;
+
+pub fn from_f32(v: f32) -> i32 {
+    (v * ONE as f32).round() as i32
+}
+
+pub fn to_f32(v: i32) -> f32 {
+    v as f32 / ONE as f32
+}
+
+pub fn mul(a: i32, b: i32) -> i32 {
+    ((a as i64 * b as i64) >> FRAC_BITS) as i32
+}
+
+pub fn div(a: i32, b: i32) -> i32 {
+    if b == 0 {
+        return if a < 0 { i32::MIN } else { i32::MAX };
+    }
+    let q = ((a as i64) << FRAC_BITS) / b as i64;
+    q.max(i32::MIN as i64).min(i32::MAX as i64) as i32
+}
+
+// The table step closest to an angle in degrees
+pub fn angle(degrees: f32) -> i32 {
+    ((degrees * ANGLES as f32 / 360.0).round() as i32).rem_euclid(ANGLES)
+}
+
+pub struct Trig {
+    sin: Vec<i32>,
+    tan: Vec<i32>,
+}
+
+impl Trig {
+    pub fn new() -> Trig {
+        let sin = (0..ANGLES)
+            .map(|a| (sin_of_step(a) * ONE as f64).round() as i32)
+            .collect();
+
+        let tan = (0..ANGLES)
+            .map(|a| {
+                let (s, c) = (sin_of_step(a), sin_of_step(a + QUARTER));
+                let tan = if c == 0.0 {
+                    if s < 0.0 {
+                        -MAX_TAN
+                    } else {
+                        MAX_TAN
+                    }
+                } else {
+                    ((s / c) * ONE as f64).round() as i64
+                };
+                tan.max(-MAX_TAN).min(MAX_TAN) as i32
+            })
+            .collect();
+
+        Trig { sin, tan }
+    }
+
+    pub fn sin(&self, angle: i32) -> i32 {
+        self.sin[angle.rem_euclid(ANGLES) as usize]
+    }
+
+    pub fn cos(&self, angle: i32) -> i32 {
+        self.sin(angle + QUARTER)
+    }
+
+    pub fn tan(&self, angle: i32) -> i32 {
+        self.tan[angle.rem_euclid(ANGLES) as usize]
+    }
+
+    // 1 / tan, without the division: tan(a + 90) is -cot(a)
+    pub fn cot(&self, angle: i32) -> i32 {
+        -self.tan(angle + QUARTER)
+    }
+
+    // The angle between -90 and 90 degrees whose tangent is closest to `v`. tan only
+    // goes up in there, so it's a binary search through the table.
+    pub fn atan(&self, v: i32) -> i32 {
+        let (mut low, mut high) = (1 - QUARTER, QUARTER - 1);
+        while low < high {
+            let mid = (low + high).div_euclid(2);
+            if self.tan(mid) < v {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        // `low` is the first one that isn't below `v`, the one before might be closer
+        if low > 1 - QUARTER && v - self.tan(low - 1) < self.tan(low) - v {
+            low - 1
+        } else {
+            low
+        }
+    }
+}
+
+// Sine of a table step, folded into the first quadrant and worked out with a Taylor
+// series, no libm
+fn sin_of_step(angle: i32) -> f64 {
+    let angle = angle.rem_euclid(ANGLES);
+    let (step, sign) = match angle / QUARTER {
+        0 => (angle, 1.0),
+        1 => (2 * QUARTER - angle, 1.0),
+        2 => (angle - 2 * QUARTER, -1.0),
+        _ => (ANGLES - angle, -1.0),
+    };
+
+    let x = step as f64 / QUARTER as f64 * std::f64::consts::FRAC_PI_2;
+    let x2 = x * x;
+    let mut term = x;
+    let mut sum = x;
+    // Up to x^21, way past what 16 bits of fraction need this close to zero
+    for n in 1..=10 {
+        term = -term * x2 / ((2 * n) as f64 * (2 * n + 1) as f64);
+        sum += term;
+    }
+
+    sign * sum
+}
@@ -0,0 +1,75 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FogMode {
+    // Fog grows evenly from nothing at `start` to fully opaque at `end`
+    Linear,
+    // Fog thickens quickly after `start` and is practically opaque by `end`
+    Exponential,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fog {
+    pub color: [u8; 3],
+    pub start: f32,
+    pub end: f32,
+    pub mode: FogMode,
+}
+
+impl Fog {
+    pub fn new(color: [u8; 3], start: f32, end: f32, mode: FogMode) -> Fog {
+        Fog {
+            color,
+            start,
+            end,
+            mode,
+        }
+    }
+
+    // No fog at all, things just fade to black with distance
+    pub fn none() -> Fog {
+        Fog::new([0, 0, 0], f32::MAX, f32::MAX, FogMode::Linear)
+    }
+
+    // How much of the fog color covers something `distance` away, from 0 to 1
+    pub fn amount(&self, distance: f32) -> f32 {
+        if distance <= self.start {
+            return 0.0;
+        }
+
+        let range = (self.end - self.start).max(std::f32::EPSILON);
+        let t = (distance - self.start) / range;
+
+        let amount = match self.mode {
+            FogMode::Linear => t,
+            // e^-4 is about 0.018, close enough to call it opaque at `end`
+            FogMode::Exponential => 1.0 - exp_falloff(t),
+        };
+
+        amount.min(1.0).max(0.0)
+    }
+
+    pub fn apply(&self, (r, g, b): (u8, u8, u8), amount: f32) -> (u8, u8, u8) {
+        if amount <= 0.0 {
+            return (r, g, b);
+        }
+
+        let [fr, fg, fb] = self.color;
+        let blend = |c: u8, f: u8| (c as f32 * (1.0 - amount) + f as f32 * amount) as u8;
+
+        (blend(r, fr), blend(g, fg), blend(b, fb))
+    }
+}
+
+// About e^(-4t), as (1 - 4t/256)^256 so it's only multiplications, no libm
+fn exp_falloff(t: f32) -> f32 {
+    let mut v = (1.0 - 4.0 * t / 256.0).max(0.0);
+    for _ in 0..8 {
+        v *= v;
+    }
+    v
+}
+
+impl Default for Fog {
+    fn default() -> Fog {
+        Fog::none()
+    }
+}
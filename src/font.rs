@@ -25,6 +25,12 @@ pub struct Text<'ttf> {
 
 impl<'ttf> Text<'ttf> {
     pub fn draw(&self, string: &str, buf: &mut [u8]) -> Result<(), String> {
+        self.draw_at(string, 0, 0, buf)
+    }
+
+    // Draws with the top left corner of the text at (left, top), cutting off whatever
+    // goes past the edges of the screen
+    pub fn draw_at(&self, string: &str, left: i32, top: i32, buf: &mut [u8]) -> Result<(), String> {
         let font_surface = self
             .font
             .render(string)
@@ -34,7 +40,11 @@ impl<'ttf> Text<'ttf> {
         font_surface.with_lock(|data| {
             for x in 0..font_surface.width() {
                 for y in 0..font_surface.height() {
-                    let dst_idx = (((320 * y) + x) * 4) as usize;
+                    let (screen_x, screen_y) = (left + x as i32, top + y as i32);
+                    if screen_x < 0 || screen_x >= 320 || screen_y < 0 {
+                        continue;
+                    }
+                    let dst_idx = (((320 * screen_y) + screen_x) * 4) as usize;
                     let dst = buf.get_mut(dst_idx..dst_idx + 4);
 
                     let src_idx = (((font_surface.width() * y) + x) * 4) as usize;
@@ -89,6 +89,9 @@ impl Game {
 
             state.draw(self.pixels.get_frame())?;
 
+            for (x, y, text) in state.hud() {
+                font.draw_at(&text, x, y, self.pixels.get_frame())?;
+            }
             font.draw(&format!("{:.0}", fps), self.pixels.get_frame())?;
 
             self.pixels.render().map_err(|e| e.to_string())?;
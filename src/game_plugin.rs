@@ -1,6 +1,8 @@
 use bevy::prelude::*;
-use sdl2::keyboard::Keycode;
 
+use crate::camera::{split_screen, Camera, Layout, DEFAULT_FOV, MAX_PITCH};
+use crate::controls::{Controls, Look};
+use crate::light::LightSource;
 use crate::{Keypress, MouseMotion};
 
 pub struct GamePlugin;
@@ -80,44 +82,92 @@ impl Position {
     }
 }
 
-pub struct Player;
+pub struct Player {
+    // Which player this is, from 0
+    pub number: usize,
+}
+
+// How many people are playing on this screen, and how it's split between them
+pub struct SplitScreen {
+    pub players: usize,
+    pub layout: Layout,
+}
+
+// Where the players start, in the order they join
+const SPAWNS: [(f32, f32); 4] = [(30., 30.), (150., 30.), (30., 90.), (150., 90.)];
+// Degrees a second players without a mouse turn at
+const KEY_TURN_SPEED: f32 = 120.0;
+
+// How far in the binoculars zoom, and how much the zoom changes every second
+const ZOOM_IN: f32 = 3.0;
+const ZOOM_SPEED: f32 = 8.0;
 
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.add_startup_system(spawn.system())
             .add_system(move_camera.system())
+            .add_system(zoom.system())
             .add_system(movement.system());
     }
 }
 
-fn spawn(mut commands: Commands) {
-    commands.spawn((Position::new(30., 30.), Player, Rotation::new(0.0)));
+fn spawn(mut commands: Commands, split: Res<SplitScreen>) {
+    let viewports = split_screen(crate::resulting_resolution, split.players, split.layout);
+    for (number, viewport) in viewports.into_iter().enumerate() {
+        let (x, y) = SPAWNS[number];
+        commands.spawn((
+            Position::new(x, y),
+            Player { number },
+            Controls::for_player(number),
+            Rotation::new(0.0),
+            LightSource::torch(),
+            Camera::new(DEFAULT_FOV, viewport),
+        ));
+    }
 }
 
 fn movement(
     keypress: Res<Keypress>,
     time: Res<Time>,
+    controls: &Controls,
     mut position: Mut<Position>,
     rotation: &Rotation,
 ) {
     let direction = rotation.direction();
-    if keypress.is(Keycode::W) {
+    if keypress.is(controls.forward) {
         *position = position.move_towards(direction, time.delta_seconds);
     }
 
-    if keypress.is(Keycode::S) {
+    if keypress.is(controls.back) {
         *position = position.move_towards(-direction, time.delta_seconds);
     }
 
-    if keypress.is(Keycode::A) {
+    if keypress.is(controls.left) {
         *position = position.move_towards(rotation.rotated(-90.).direction(), time.delta_seconds);
     }
 
-    if keypress.is(Keycode::D) {
+    if keypress.is(controls.right) {
         *position = position.move_towards(rotation.rotated(90.).direction(), time.delta_seconds);
     }
 }
 
+// Zooms a player's camera in while their zoom key is held, and back out when it's let go
+fn zoom(keypress: Res<Keypress>, time: Res<Time>, controls: &Controls, mut camera: Mut<Camera>) {
+    let target = if keypress.is(controls.zoom) {
+        ZOOM_IN
+    } else {
+        1.0
+    };
+    // Moves a fixed amount instead of easing, so it ends up exactly on the target
+    // and the ray tables don't have to be worked out again every frame
+    let step = ZOOM_SPEED * time.delta_seconds;
+    camera.zoom = if camera.zoom < target {
+        (camera.zoom + step).min(target)
+    } else {
+        (camera.zoom - step).max(target)
+    };
+}
+
 #[derive(Default, Clone)]
 pub struct Rotation {
     cur_degrees: f32,
@@ -196,9 +246,32 @@ impl Rotation {
     }
 }
 
-fn move_camera(mouse_motion: Res<MouseMotion>, time: Res<Time>, mut rotation: Mut<Rotation>) {
-    if mouse_motion.x == 0 {
-        return;
+fn move_camera(
+    mouse_motion: Res<MouseMotion>,
+    keypress: Res<Keypress>,
+    time: Res<Time>,
+    controls: &Controls,
+    mut rotation: Mut<Rotation>,
+    mut camera: Mut<Camera>,
+) {
+    match controls.look {
+        Look::Mouse => {
+            if mouse_motion.x != 0 {
+                rotation.add(mouse_motion.x as f32 * time.delta_seconds * 250.0);
+            }
+            if mouse_motion.y != 0 {
+                camera.pitch = (camera.pitch - mouse_motion.y as f32 * time.delta_seconds * 100.0)
+                    .max(-MAX_PITCH)
+                    .min(MAX_PITCH);
+            }
+        }
+        Look::Keys { left, right } => {
+            if keypress.is(left) {
+                rotation.add(-KEY_TURN_SPEED * time.delta_seconds);
+            }
+            if keypress.is(right) {
+                rotation.add(KEY_TURN_SPEED * time.delta_seconds);
+            }
+        }
     }
-    rotation.add(mouse_motion.x as f32 * time.delta_seconds * 250.0);
 }
@@ -0,0 +1,318 @@
+use crate::TILE_SIZE;
+
+// Distance at which a light has dropped to half its intensity
+const FALLOFF_DISTANCE: f32 = (TILE_SIZE * 2) as f32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightAnimation {
+    Steady,
+    // Mostly on, dropping out at random like a broken fluorescent tube
+    Flicker { seed: u32 },
+    // Smoothly fades in and out every `period` seconds
+    Pulse { period: f32 },
+    // On for `duty` of every `period` seconds, off for the rest
+    Strobe { period: f32, duty: f32 },
+    // Small smooth random wobble, like a flame
+    Candle { seed: u32 },
+}
+
+impl LightAnimation {
+    // How bright the animated light is at the given time, from 0 to 1.
+    // The same seed and time always give back the same brightness.
+    pub fn brightness(&self, time: f64) -> f32 {
+        match *self {
+            LightAnimation::Steady => 1.0,
+            LightAnimation::Flicker { seed } => {
+                let step = (time * 12.0).floor() as u32;
+                if noise(seed, step) < 0.2 {
+                    0.15
+                } else {
+                    1.0
+                }
+            }
+            LightAnimation::Pulse { period } => {
+                let phase = (time % period as f64) as f32 / period;
+                // Up and down in a straight line, smoothstepped so it eases at the ends
+                // like a sine wave would, no libm
+                let triangle = 1.0 - (phase * 2.0 - 1.0).abs();
+                let wave = triangle * triangle * (3.0 - 2.0 * triangle);
+                0.3 + wave * 0.7
+            }
+            LightAnimation::Strobe { period, duty } => {
+                let phase = (time % period as f64) as f32 / period;
+                if phase < duty {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            LightAnimation::Candle { seed } => {
+                let t = time * 8.0;
+                let step = t.floor() as u32;
+                let fract = t.fract() as f32;
+                // Smoothstep between two random values so it wobbles instead of jumping
+                let fract = fract * fract * (3.0 - 2.0 * fract);
+                let a = noise(seed, step);
+                let b = noise(seed, step.wrapping_add(1));
+                0.75 + (a + (b - a) * fract) * 0.25
+            }
+        }
+    }
+}
+
+// Cheap integer hash, turned into a number between 0 and 1
+fn noise(seed: u32, n: u32) -> f32 {
+    let mut h = seed ^ n.wrapping_mul(0x9e37_79b9);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    (h & 0xffff) as f32 / 0xffff as f32
+}
+
+// Seed for lights that don't get one explicitly, so two torches don't flicker in sync
+fn seed_from_position(x: i32, y: i32) -> u32 {
+    (x as u32).wrapping_mul(73_856_093) ^ (y as u32).wrapping_mul(19_349_663)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Light {
+    pub x: i32,
+    pub y: i32,
+    pub color: [f32; 3],
+    pub radius: f32,
+    pub intensity: f32,
+    // How far from its center the light is emitted, which softens its shadows
+    pub size: f32,
+    pub animation: LightAnimation,
+}
+
+impl Light {
+    pub fn new(x: i32, y: i32, color: [f32; 3], radius: f32, intensity: f32) -> Light {
+        Light {
+            x,
+            y,
+            color,
+            radius,
+            intensity,
+            size: (TILE_SIZE / 4) as f32,
+            animation: LightAnimation::Steady,
+        }
+    }
+
+    pub fn animated(mut self, animation: LightAnimation) -> Light {
+        self.animation = animation;
+        self
+    }
+
+    pub fn torch(x: i32, y: i32) -> Light {
+        Light::new(x, y, [1.0, 0.62, 0.3], 120.0, 1.0).animated(LightAnimation::Candle {
+            seed: seed_from_position(x, y),
+        })
+    }
+
+    pub fn terminal(x: i32, y: i32) -> Light {
+        Light::new(x, y, [0.35, 0.55, 1.0], 60.0, 0.8).animated(LightAnimation::Flicker {
+            seed: seed_from_position(x, y),
+        })
+    }
+
+    // Slowly breathing green, like a status lamp
+    pub fn lamp(x: i32, y: i32) -> Light {
+        Light::new(x, y, [0.4, 1.0, 0.5], 60.0, 0.7).animated(LightAnimation::Pulse { period: 3.0 })
+    }
+
+    pub fn alarm(x: i32, y: i32) -> Light {
+        Light::new(x, y, [1.0, 0.15, 0.1], 72.0, 0.9).animated(LightAnimation::Strobe {
+            period: 1.0,
+            duty: 0.5,
+        })
+    }
+
+    // Fades the light out as it gets closer to its radius,
+    // so there's no hard edge where it stops reaching
+    pub fn window(&self, distance: f32) -> f32 {
+        if distance >= self.radius {
+            return 0.0;
+        }
+
+        let t = distance / self.radius;
+        let falloff = 1.0 - t * t * t * t;
+        falloff * falloff
+    }
+
+    // Inverse square falloff, softened by FALLOFF_DISTANCE so it
+    // doesn't blow up right next to the light
+    pub fn attenuation(&self, distance: f32) -> f32 {
+        let d = distance / FALLOFF_DISTANCE;
+        self.intensity * self.window(distance) / (1.0 + d * d)
+    }
+
+    // What this light adds to a point `distance` away from it
+    pub fn contribution(&self, distance: f32) -> [f32; 3] {
+        let [r, g, b] = self.color;
+        let attenuation = self.attenuation(distance);
+        [r * attenuation, g * attenuation, b * attenuation]
+    }
+}
+
+// The baked light of one animated light, or of all the steady ones together, so it
+// can be scaled every frame without baking again. Only the tiles between `min` and
+// `max` get lumels, the light doesn't reach any further than that.
+pub struct LightLayer {
+    pub animation: LightAnimation,
+    pub brightness: f32,
+    pub min: (i32, i32),
+    pub max: (i32, i32),
+    // Where every covered tile's lumels start in `data`, a row of tiles at a time
+    starts: Vec<usize>,
+    pub data: Vec<[f32; 3]>,
+}
+
+impl LightLayer {
+    // `lumels` says how many lumels a tile has
+    pub fn new<F>(
+        animation: LightAnimation,
+        min: (i32, i32),
+        max: (i32, i32),
+        lumels: F,
+    ) -> LightLayer
+    where
+        F: Fn((i32, i32)) -> usize,
+    {
+        let mut starts = Vec::new();
+        let mut size = 0;
+        for y in min.1..=max.1 {
+            for x in min.0..=max.0 {
+                starts.push(size);
+                size += lumels((x, y));
+            }
+        }
+
+        LightLayer {
+            animation,
+            brightness: 1.0,
+            min,
+            max,
+            starts,
+            data: vec![[0.0; 3]; size],
+        }
+    }
+
+    pub fn covers(&self, (x, y): (i32, i32)) -> bool {
+        x >= self.min.0 && y >= self.min.1 && x <= self.max.0 && y <= self.max.1
+    }
+
+    // Where the `n`th lumel of a tile is kept in `data`, if the layer covers the tile
+    pub fn lumel(&self, tile: (i32, i32), n: usize) -> Option<usize> {
+        if !self.covers(tile) {
+            return None;
+        }
+
+        let row = self.max.0 - self.min.0 + 1;
+        let i = row * (tile.1 - self.min.1) + tile.0 - self.min.0;
+        Some(self.starts[i as usize] + n)
+    }
+
+    pub fn animate(&mut self, time: f64) {
+        self.brightness = self.animation.brightness(time);
+    }
+}
+
+// Dynamic lights past this many are ignored for the frame, closest to the player first
+pub const MAX_DYNAMIC_LIGHTS: usize = 4;
+
+// Dynamic light occlusion is checked on a grid this coarse instead of per pixel.
+// It has to fit in a tile a whole number of times, so the cells line up with the tiles.
+const VISIBILITY_CELL: i32 = TILE_SIZE / 4;
+
+// Component for entities that emit light as they move around
+#[derive(Debug, Clone, Copy)]
+pub struct LightSource {
+    pub color: [f32; 3],
+    pub radius: f32,
+    pub intensity: f32,
+}
+
+impl LightSource {
+    pub fn new(color: [f32; 3], radius: f32, intensity: f32) -> LightSource {
+        LightSource {
+            color,
+            radius,
+            intensity,
+        }
+    }
+
+    pub fn torch() -> LightSource {
+        LightSource::new([1.0, 0.8, 0.55], 48.0, 0.5)
+    }
+
+    pub fn at(&self, x: f32, y: f32) -> Light {
+        Light::new(x as i32, y as i32, self.color, self.radius, self.intensity)
+    }
+}
+
+// A light that's only valid for the current frame, along with
+// which parts of the map around it it can actually see
+pub struct DynamicLight {
+    light: Light,
+    origin: (i32, i32),
+    cells: i32,
+    visibility: Vec<bool>,
+}
+
+impl DynamicLight {
+    pub fn new<F>(light: Light, is_visible: F) -> DynamicLight
+    where
+        F: Fn((i32, i32)) -> bool,
+    {
+        let reach = (light.radius as i32 / VISIBILITY_CELL) + 1;
+        let cells = reach * 2 + 1;
+        // Lined up with the tiles, so no cell is part floor and part wall, and the
+        // cells don't shift around under the walls as the light moves
+        let origin = (
+            (light.x - reach * VISIBILITY_CELL).div_euclid(VISIBILITY_CELL) * VISIBILITY_CELL,
+            (light.y - reach * VISIBILITY_CELL).div_euclid(VISIBILITY_CELL) * VISIBILITY_CELL,
+        );
+
+        let mut visibility = vec![false; (cells * cells) as usize];
+        for cy in 0..cells {
+            for cx in 0..cells {
+                let point = (
+                    origin.0 + cx * VISIBILITY_CELL + VISIBILITY_CELL / 2,
+                    origin.1 + cy * VISIBILITY_CELL + VISIBILITY_CELL / 2,
+                );
+                visibility[(cells * cy + cx) as usize] = is_visible(point);
+            }
+        }
+
+        DynamicLight {
+            light,
+            origin,
+            cells,
+            visibility,
+        }
+    }
+
+    pub fn contribution_at(&self, x: i32, y: i32) -> Option<[f32; 3]> {
+        // No libm hypot
+        let (dx, dy) = ((x - self.light.x) as f32, (y - self.light.y) as f32);
+        let dst = (dx * dx + dy * dy).sqrt();
+        if dst >= self.light.radius {
+            return None;
+        }
+
+        let cx = (x - self.origin.0).div_euclid(VISIBILITY_CELL);
+        let cy = (y - self.origin.1).div_euclid(VISIBILITY_CELL);
+        if cx < 0 || cy < 0 || cx >= self.cells || cy >= self.cells {
+            return None;
+        }
+
+        if !self.visibility[(self.cells * cy + cx) as usize] {
+            return None;
+        }
+
+        Some(self.light.contribution(dst))
+    }
+}
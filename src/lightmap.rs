@@ -0,0 +1,860 @@
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+use crate::light::{Light, LightAnimation, LightLayer};
+use crate::raycaster::Map;
+use crate::TILE_SIZE;
+
+// How many lumels fit along the edge of a tile unless the map asks for something else
+pub const DEFAULT_DENSITY: i32 = 4;
+// Past this the lightmap is bigger than the textures it lights
+pub const MAX_DENSITY: i32 = 32;
+
+// Bump whenever baking changes, so lightmaps cached by older versions get baked again
+pub const BAKE_VERSION: u32 = 4;
+
+const CACHE_MAGIC: &[u8] = b"RCLIGHTS";
+
+// Directions to the points around a light's center that shadows are tested against, for
+// soft edges. Worked out ahead of time, no libm.
+const RING: [(f32, f32); 8] = [
+    (1.0, 0.0),
+    (DIAGONAL, DIAGONAL),
+    (0.0, 1.0),
+    (-DIAGONAL, DIAGONAL),
+    (-1.0, 0.0),
+    (-DIAGONAL, -DIAGONAL),
+    (0.0, -1.0),
+    (DIAGONAL, -DIAGONAL),
+];
+const DIAGONAL: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+// How close to a wall the floor starts getting darker, and how much darker each wall makes it
+const OCCLUSION_RADIUS: f32 = (TILE_SIZE / 2) as f32;
+const OCCLUSION_STRENGTH: f32 = 0.35;
+const MIN_OCCLUSION: f32 = 0.3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Face {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Face {
+    const ALL: [Face; 4] = [Face::North, Face::East, Face::South, Face::West];
+
+    fn index(self) -> usize {
+        match self {
+            Face::North => 0,
+            Face::East => 1,
+            Face::South => 2,
+            Face::West => 3,
+        }
+    }
+
+    // The neighbouring tile this face looks into
+    fn facing(self, (x, y): (i32, i32)) -> (i32, i32) {
+        match self {
+            Face::North => (x, y - 1),
+            Face::East => (x + 1, y),
+            Face::South => (x, y + 1),
+            Face::West => (x - 1, y),
+        }
+    }
+}
+
+// Where a tile keeps its lumels: one slot for each wall face, plus one for the floor
+const FLOOR: usize = 4;
+type Slots = [Option<usize>; 5];
+
+// The layer every steady light bakes into
+const STEADY: usize = 0;
+
+// Baked light stored only where it can be seen: a grid of lumels on every
+// floor cell, and a row of them along every wall face that borders a floor.
+// Lumels sit on the corners of their grid so neighbouring cells agree on
+// their shared edges, which keeps the bilinear sampling seamless.
+pub struct Lightmap {
+    density: i32,
+    width: i32,
+    height: i32,
+    slots: Vec<Slots>,
+    // Which lights could reach every tile, and which layer each light bakes into.
+    // Steady lights all share one layer, every animated light gets its own.
+    candidates: Vec<Vec<usize>>,
+    layer_of: Vec<usize>,
+    pub layers: Vec<LightLayer>,
+    // The animated layers covering every tile, so sampling skips the ones that can't reach
+    covering: Vec<Vec<usize>>,
+    // How much of the ambient light reaches every lumel, from 0 to 1
+    occlusion: Vec<f32>,
+}
+
+// A lumel whose line to a light goes through a tile that's about to change
+pub struct Crossing {
+    tile: (i32, i32),
+    slot: usize,
+    lumel: usize,
+    light: usize,
+    point: (f32, f32),
+    was_visible: f32,
+}
+
+impl Lightmap {
+    pub fn empty() -> Lightmap {
+        Lightmap {
+            density: DEFAULT_DENSITY,
+            width: 0,
+            height: 0,
+            slots: Vec::new(),
+            candidates: Vec::new(),
+            layer_of: Vec::new(),
+            layers: Vec::new(),
+            covering: Vec::new(),
+            occlusion: Vec::new(),
+        }
+    }
+
+    pub fn bake(map: &Map, density: i32) -> Lightmap {
+        let mut lightmap = Lightmap::prepare(map, density);
+        for y in 0..lightmap.height {
+            for x in 0..lightmap.width {
+                for slot in 0..=FLOOR {
+                    lightmap.bake_slot(map, (x, y), slot);
+                }
+            }
+        }
+
+        lightmap
+    }
+
+    // Lays out the lightmap for the given map, with every lumel still dark
+    fn prepare(map: &Map, density: i32) -> Lightmap {
+        let density = density.max(1);
+        let (slots, size) = layout(map, density);
+        let (width, height) = (map.width(), map.height());
+        let lumels = |(x, y): (i32, i32)| tile_lumels(&slots[(width * y + x) as usize], density);
+
+        // Animated lights only get lumels where they reach, a tile further out so the
+        // wall faces at the edge are in there too
+        let mut layers = vec![LightLayer::new(
+            LightAnimation::Steady,
+            (0, 0),
+            (width - 1, height - 1),
+            &lumels,
+        )];
+        let mut layer_of = Vec::with_capacity(map.lights().len());
+        for light in map.lights() {
+            if light.animation == LightAnimation::Steady {
+                layer_of.push(STEADY);
+                continue;
+            }
+
+            let (min, max) = reach_bounds(light, width, height);
+            let min = ((min.0 - 1).max(0), (min.1 - 1).max(0));
+            let max = ((max.0 + 1).min(width - 1), (max.1 + 1).min(height - 1));
+            layers.push(LightLayer::new(light.animation, min, max, &lumels));
+            layer_of.push(layers.len() - 1);
+        }
+
+        let mut covering = vec![Vec::new(); (width * height) as usize];
+        for (l, layer) in layers.iter().enumerate().skip(1) {
+            for y in layer.min.1..=layer.max.1 {
+                for x in layer.min.0..=layer.max.0 {
+                    covering[(width * y + x) as usize].push(l);
+                }
+            }
+        }
+
+        Lightmap {
+            density,
+            width,
+            height,
+            slots,
+            candidates: lights_by_tile(map),
+            layer_of,
+            layers,
+            covering,
+            occlusion: vec![1.0; size],
+        }
+    }
+
+    // Reads back a bake written by `save`. Fails if it was baked from anything
+    // other than what `hash` describes, or doesn't fit the map anymore.
+    pub fn load(path: &Path, map: &Map, density: i32, hash: u64) -> Result<Lightmap, String> {
+        let bytes = fs::read(path).map_err(|e| e.to_string())?;
+        let mut reader = Reader {
+            bytes: &bytes,
+            at: 0,
+        };
+
+        if reader.take(CACHE_MAGIC.len())? != CACHE_MAGIC {
+            return Err(format!("{} is not a lightmap", path.display()));
+        }
+
+        if reader.u64()? != hash {
+            return Err(format!("{} was baked from a different map", path.display()));
+        }
+
+        let mut lightmap = Lightmap::prepare(map, density);
+        let size = reader.u64()? as usize;
+        let layers = reader.u64()? as usize;
+        if size != lightmap.occlusion.len() || layers != lightmap.layers.len() {
+            return Err(format!("{} doesn't fit the map", path.display()));
+        }
+
+        for layer in lightmap.layers.iter_mut() {
+            if reader.u64()? as usize != layer.data.len() {
+                return Err(format!("{} doesn't fit the map", path.display()));
+            }
+
+            for lumel in layer.data.iter_mut() {
+                *lumel = [reader.f32()?, reader.f32()?, reader.f32()?];
+            }
+        }
+
+        for occlusion in lightmap.occlusion.iter_mut() {
+            *occlusion = reader.f32()?;
+        }
+
+        Ok(lightmap)
+    }
+
+    pub fn save(&self, path: &Path, hash: u64) -> Result<(), String> {
+        let size = self.occlusion.len();
+        let lumels: usize = self.layers.iter().map(|l| l.data.len()).sum();
+
+        let mut bytes = Vec::with_capacity(
+            CACHE_MAGIC.len() + 24 + 8 * self.layers.len() + 12 * lumels + 4 * size,
+        );
+        bytes.extend_from_slice(CACHE_MAGIC);
+        bytes.extend_from_slice(&hash.to_le_bytes());
+        bytes.extend_from_slice(&(size as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.layers.len() as u64).to_le_bytes());
+        for layer in &self.layers {
+            bytes.extend_from_slice(&(layer.data.len() as u64).to_le_bytes());
+            for lumel in &layer.data {
+                for channel in lumel {
+                    bytes.extend_from_slice(&channel.to_le_bytes());
+                }
+            }
+        }
+
+        for occlusion in &self.occlusion {
+            bytes.extend_from_slice(&occlusion.to_le_bytes());
+        }
+
+        fs::write(path, bytes).map_err(|e| e.to_string())
+    }
+
+    // Lights every lumel in one of the tile's slots from scratch
+    fn bake_slot(&mut self, map: &Map, tile: (i32, i32), slot: usize) {
+        let offset = match self.slots[self.index(tile)][slot] {
+            Some(offset) => offset,
+            None => return,
+        };
+
+        let candidates = self.candidates[self.slot_candidates(tile, slot)].clone();
+        let start = self.tile_start(tile);
+        let layers = self.layers_at(tile);
+        for (i, point) in self.slot_points(tile, slot).into_iter().enumerate() {
+            for &l in &layers {
+                if let Some(lumel) = self.layers[l].lumel(tile, offset + i - start) {
+                    self.layers[l].data[lumel] = [0.0; 3];
+                }
+            }
+
+            for &l in &candidates {
+                let light = &map.lights()[l];
+                let dst = match reaches(light, point) {
+                    Some(dst) => dst,
+                    None => continue,
+                };
+
+                let visible = visibility(map, light, point);
+                if visible > 0.0 {
+                    self.add(l, tile, offset + i, light.contribution(dst), visible);
+                }
+            }
+        }
+
+        self.occlude_slot(map, tile, slot);
+    }
+
+    // Darkens the lumels of a slot that sit close to walls or tucked into corners
+    fn occlude_slot(&mut self, map: &Map, tile: (i32, i32), slot: usize) {
+        let offset = match self.slots[self.index(tile)][slot] {
+            Some(offset) => offset,
+            None => return,
+        };
+
+        // Wall faces are checked from the floor in front of them, without counting their own wall
+        let (from, ignore) = if slot == FLOOR {
+            (tile, None)
+        } else {
+            (Face::ALL[slot].facing(tile), Some(tile))
+        };
+
+        for (i, point) in self.slot_points(tile, slot).into_iter().enumerate() {
+            self.occlusion[offset + i] = occlusion(map, from, point, ignore);
+        }
+    }
+
+    // Finds every lumel that sees (or doesn't see) a light through the given tile.
+    // Has to be called before the tile changes, so we know what they saw before.
+    pub fn crossings(&self, map: &Map, tile: (i32, i32)) -> Vec<Crossing> {
+        let mut crossings = Vec::new();
+        for &l in &self.candidates[self.index(tile)] {
+            let light = &map.lights()[l];
+            let (min, max) = reach_bounds(light, self.width, self.height);
+
+            for y in min.1..=max.1 {
+                for x in min.0..=max.0 {
+                    for slot in 0..=FLOOR {
+                        if self.slots[self.index((x, y))][slot].is_none() {
+                            continue;
+                        }
+
+                        if !self.candidates[self.slot_candidates((x, y), slot)].contains(&l) {
+                            continue;
+                        }
+
+                        for (i, point) in self.slot_points((x, y), slot).into_iter().enumerate() {
+                            if reaches(light, point).is_none() {
+                                continue;
+                            }
+
+                            let samples = light_samples(light);
+                            if !samples.iter().any(|s| crosses_tile(point, *s, tile)) {
+                                continue;
+                            }
+
+                            crossings.push(Crossing {
+                                tile: (x, y),
+                                slot,
+                                lumel: i,
+                                light: l,
+                                point,
+                                was_visible: visibility(map, light, point),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        crossings
+    }
+
+    // Catches up with a tile that changed since `crossings` was called: moves the
+    // lumels that are still around into the new layout, bakes the ones that are new,
+    // and adds or takes away lights from the lumels whose view of them changed.
+    pub fn retile(&mut self, map: &Map, tile: (i32, i32), crossings: Vec<Crossing>) {
+        let (slots, size) = layout(map, self.density);
+        let (width, density) = (self.width, self.density);
+        let lumels = |(x, y): (i32, i32)| tile_lumels(&slots[(width * y + x) as usize], density);
+
+        let layers: Vec<LightLayer> = self
+            .layers
+            .iter()
+            .map(|old| {
+                let mut layer = LightLayer::new(old.animation, old.min, old.max, &lumels);
+                layer.brightness = old.brightness;
+
+                for y in old.min.1..=old.max.1 {
+                    for x in old.min.0..=old.max.0 {
+                        let (old_slots, new_slots) = (
+                            &self.slots[(width * y + x) as usize],
+                            &slots[(width * y + x) as usize],
+                        );
+                        let (old_start, new_start) = (tile_start(old_slots), tile_start(new_slots));
+                        for slot in 0..=FLOOR {
+                            if let (Some(from), Some(to)) = (old_slots[slot], new_slots[slot]) {
+                                let len = slot_len(slot, density);
+                                let from = old.lumel((x, y), from - old_start).unwrap();
+                                let to = layer.lumel((x, y), to - new_start).unwrap();
+                                layer.data[to..to + len]
+                                    .copy_from_slice(&old.data[from..from + len]);
+                            }
+                        }
+                    }
+                }
+
+                layer
+            })
+            .collect();
+
+        let mut occlusion = vec![1.0; size];
+
+        for (old, new) in self.slots.iter().zip(slots.iter()) {
+            for slot in 0..=FLOOR {
+                if let (Some(old), Some(new)) = (old[slot], new[slot]) {
+                    let len = self.slot_len(slot);
+                    occlusion[new..new + len].copy_from_slice(&self.occlusion[old..old + len]);
+                }
+            }
+        }
+
+        let old_slots = std::mem::replace(&mut self.slots, slots);
+        self.layers = layers;
+        self.occlusion = occlusion;
+
+        // Floors right next to the changed tile can be occluded differently, and so can
+        // the wall faces looking into them, which can be another tile further out
+        for y in (tile.1 - 2).max(0)..=(tile.1 + 2).min(self.height - 1) {
+            for x in (tile.0 - 2).max(0)..=(tile.0 + 2).min(self.width - 1) {
+                for slot in 0..=FLOOR {
+                    self.occlude_slot(map, (x, y), slot);
+                }
+            }
+        }
+
+        // Nothing to carry over for slots that didn't exist before
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let t = self.index((x, y));
+                for slot in 0..=FLOOR {
+                    if old_slots[t][slot].is_none() && self.slots[t][slot].is_some() {
+                        self.bake_slot(map, (x, y), slot);
+                    }
+                }
+            }
+        }
+
+        for crossing in crossings {
+            let t = self.index(crossing.tile);
+            if old_slots[t][crossing.slot].is_none() {
+                continue;
+            }
+
+            let offset = match self.slots[t][crossing.slot] {
+                Some(offset) => offset,
+                None => continue,
+            };
+
+            let light = &map.lights()[crossing.light];
+            let change = visibility(map, light, crossing.point) - crossing.was_visible;
+            if change == 0.0 {
+                continue;
+            }
+
+            let dst = length(
+                crossing.point.0 - light.x as f32,
+                crossing.point.1 - light.y as f32,
+            );
+            self.add(
+                crossing.light,
+                crossing.tile,
+                offset + crossing.lumel,
+                light.contribution(dst),
+                change,
+            );
+        }
+    }
+
+    // Adds some amount of a light's contribution to one of a tile's lumels,
+    // or takes it away when negative
+    fn add(
+        &mut self,
+        light: usize,
+        tile: (i32, i32),
+        lumel: usize,
+        [r, g, b]: [f32; 3],
+        amount: f32,
+    ) {
+        let n = lumel - self.tile_start(tile);
+        let layer = &mut self.layers[self.layer_of[light]];
+        let lumel = match layer.lumel(tile, n) {
+            Some(lumel) => lumel,
+            None => return,
+        };
+
+        let data = &mut layer.data[lumel];
+        data[0] = (data[0] + r * amount).max(0.0);
+        data[1] = (data[1] + g * amount).max(0.0);
+        data[2] = (data[2] + b * amount).max(0.0);
+    }
+
+    fn index(&self, (x, y): (i32, i32)) -> usize {
+        (self.width * y + x) as usize
+    }
+
+    fn slot_len(&self, slot: usize) -> usize {
+        slot_len(slot, self.density)
+    }
+
+    // Where a tile's lumels start, layers count a tile's lumels from there
+    fn tile_start(&self, tile: (i32, i32)) -> usize {
+        tile_start(&self.slots[self.index(tile)])
+    }
+
+    // Every layer with lumels on the tile, the steady one first
+    fn layers_at(&self, tile: (i32, i32)) -> Vec<usize> {
+        let mut layers = vec![STEADY];
+        layers.extend_from_slice(&self.covering[self.index(tile)]);
+        layers
+    }
+
+    // The tile whose candidate lights apply to a slot, which for a wall face
+    // is the floor it's looking into
+    fn slot_candidates(&self, tile: (i32, i32), slot: usize) -> usize {
+        if slot == FLOOR {
+            self.index(tile)
+        } else {
+            self.index(Face::ALL[slot].facing(tile))
+        }
+    }
+
+    // Where in the world every lumel of a slot sits, in the order they're stored
+    fn slot_points(&self, (x, y): (i32, i32), slot: usize) -> Vec<(f32, f32)> {
+        let tile_size = TILE_SIZE as f32;
+        let density = self.density;
+        let step = tile_size / density as f32;
+        let origin = ((x * TILE_SIZE) as f32, (y * TILE_SIZE) as f32);
+
+        if slot == FLOOR {
+            // Lumels on the edge of a cell belong to the neighbour too, keep them
+            // just inside this cell so the light test doesn't start inside a wall
+            let inside = |v: f32, edge: f32| v.max(edge + 0.01).min(edge + tile_size - 0.01);
+
+            let mut points = Vec::with_capacity(self.slot_len(slot));
+            for j in 0..=density {
+                for i in 0..=density {
+                    points.push((
+                        inside(origin.0 + i as f32 * step, origin.0),
+                        inside(origin.1 + j as f32 * step, origin.1),
+                    ));
+                }
+            }
+            return points;
+        }
+
+        (0..=density)
+            .map(|i| {
+                let along = i as f32 * step;
+                // Nudged a little into the floor so the wall doesn't shadow itself
+                match Face::ALL[slot] {
+                    Face::North => (origin.0 + along, origin.1 - 1.0),
+                    Face::South => (origin.0 + along, origin.1 + tile_size),
+                    Face::West => (origin.0 - 1.0, origin.1 + along),
+                    Face::East => (origin.0 + tile_size, origin.1 + along),
+                }
+            })
+            .collect()
+    }
+
+    pub fn animate(&mut self, time: f64) {
+        for layer in self.layers.iter_mut() {
+            layer.animate(time);
+        }
+    }
+
+    // The light at a point on the floor, along with how occluded it is
+    pub fn sample_floor(&self, x: f32, y: f32) -> ([f32; 3], f32) {
+        let tile_size = TILE_SIZE as f32;
+        let (tx, ty) = (
+            (x / tile_size).floor() as i32,
+            (y / tile_size).floor() as i32,
+        );
+        if tx < 0 || ty < 0 || tx >= self.width || ty >= self.height {
+            return ([0.0; 3], 1.0);
+        }
+
+        let offset = match self.slots[self.index((tx, ty))][FLOOR] {
+            Some(offset) => offset,
+            None => return ([0.0; 3], 1.0),
+        };
+
+        let d = self.density;
+        let to_lumel = |v: f32, t: i32| {
+            let u = ((v - (t * TILE_SIZE) as f32) / tile_size * d as f32)
+                .max(0.0)
+                .min(d as f32);
+            let i = (u.floor() as i32).min(d - 1);
+            (i, u - i as f32)
+        };
+        let (i, fu) = to_lumel(x, tx);
+        let (j, fv) = to_lumel(y, ty);
+
+        let row = d + 1;
+        let corners = [
+            (offset + (j * row + i) as usize, (1.0 - fu) * (1.0 - fv)),
+            (offset + (j * row + i + 1) as usize, fu * (1.0 - fv)),
+            (offset + ((j + 1) * row + i) as usize, (1.0 - fu) * fv),
+            (offset + ((j + 1) * row + i + 1) as usize, fu * fv),
+        ];
+
+        self.blend((tx, ty), &corners)
+    }
+
+    // `along` is how far along the face the point is, from 0 to 1
+    pub fn sample_wall(&self, (tx, ty): (i32, i32), face: Face, along: f32) -> ([f32; 3], f32) {
+        if tx < 0 || ty < 0 || tx >= self.width || ty >= self.height {
+            return ([0.0; 3], 1.0);
+        }
+
+        let offset = match self.slots[self.index((tx, ty))][face.index()] {
+            Some(offset) => offset,
+            None => return ([0.0; 3], 1.0),
+        };
+
+        let d = self.density;
+        let u = (along * d as f32).max(0.0).min(d as f32);
+        let i = (u.floor() as i32).min(d - 1);
+        let fu = u - i as f32;
+
+        let corners = [
+            (offset + i as usize, 1.0 - fu),
+            (offset + i as usize + 1, fu),
+        ];
+
+        self.blend((tx, ty), &corners)
+    }
+
+    // Weighted sum of the given lumels of a tile, across every layer covering it at its
+    // current brightness, and how occluded they are
+    fn blend(&self, tile: (i32, i32), lumels: &[(usize, f32)]) -> ([f32; 3], f32) {
+        let start = self.tile_start(tile);
+        let animated = &self.covering[self.index(tile)];
+
+        let mut total = [0.0; 3];
+        for &l in std::iter::once(&STEADY).chain(animated.iter()) {
+            let layer = &self.layers[l];
+            for &(lumel, weight) in lumels {
+                let [r, g, b] = match layer.lumel(tile, lumel - start) {
+                    Some(lumel) => layer.data[lumel],
+                    None => continue,
+                };
+                let weight = weight * layer.brightness;
+                total[0] += r * weight;
+                total[1] += g * weight;
+                total[2] += b * weight;
+            }
+        }
+
+        let occlusion = lumels
+            .iter()
+            .map(|&(lumel, weight)| self.occlusion[lumel] * weight)
+            .sum();
+
+        (total, occlusion)
+    }
+}
+
+fn slot_len(slot: usize, density: i32) -> usize {
+    if slot == FLOOR {
+        ((density + 1) * (density + 1)) as usize
+    } else {
+        (density + 1) as usize
+    }
+}
+
+// How many lumels a tile has across all its slots
+fn tile_lumels(slots: &Slots, density: i32) -> usize {
+    (0..=FLOOR)
+        .filter(|&slot| slots[slot].is_some())
+        .map(|slot| slot_len(slot, density))
+        .sum()
+}
+
+// A tile's slots are laid out one after the other, so its lumels start at its first one
+fn tile_start(slots: &Slots) -> usize {
+    slots.iter().flatten().min().copied().unwrap_or(0)
+}
+
+// Works out which slots every tile has and where their lumels start,
+// along with how many lumels there are in total
+fn layout(map: &Map, density: i32) -> (Vec<Slots>, usize) {
+    let (width, height) = (map.width(), map.height());
+    let floor_lumels = ((density + 1) * (density + 1)) as usize;
+    let face_lumels = (density + 1) as usize;
+
+    let mut size = 0;
+    let mut slots = vec![[None; 5]; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let idx = (width * y + x) as usize;
+            if !map.is_blocking_at((x, y)) {
+                slots[idx][FLOOR] = Some(size);
+                size += floor_lumels;
+                continue;
+            }
+
+            for face in Face::ALL.iter() {
+                let (fx, fy) = face.facing((x, y));
+                if fx < 0 || fy < 0 || fx >= width || fy >= height {
+                    continue;
+                }
+
+                if !map.is_blocking_at((fx, fy)) {
+                    slots[idx][face.index()] = Some(size);
+                    size += face_lumels;
+                }
+            }
+        }
+    }
+
+    (slots, size)
+}
+
+// The distance to the light, if it's close enough to be reached by it
+fn reaches(light: &Light, (x, y): (f32, f32)) -> Option<f32> {
+    let dst = length(x - light.x as f32, y - light.y as f32);
+    if dst < light.radius {
+        Some(dst)
+    } else {
+        None
+    }
+}
+
+// The center of the light, surrounded by a ring of points at the edge of its area
+fn light_samples(light: &Light) -> Vec<(f32, f32)> {
+    let center = (light.x as f32, light.y as f32);
+    if light.size <= 0.0 {
+        return vec![center];
+    }
+
+    let mut samples = Vec::with_capacity(RING.len() + 1);
+    samples.push(center);
+    for (x, y) in RING.iter() {
+        samples.push((center.0 + x * light.size, center.1 + y * light.size));
+    }
+
+    samples
+}
+
+// How much of a light can be seen from the given point, from 0 to 1
+fn visibility(map: &Map, light: &Light, point: (f32, f32)) -> f32 {
+    let samples = light_samples(light);
+    let visible = samples
+        .iter()
+        .filter(|sample| map.is_lit_by(point, **sample))
+        .count();
+
+    visible as f32 / samples.len() as f32
+}
+
+// How much ambient light reaches a point in the given floor tile, darker
+// the closer it is to walls and darker still where two of them meet
+fn occlusion(
+    map: &Map,
+    (tx, ty): (i32, i32),
+    (x, y): (f32, f32),
+    ignore: Option<(i32, i32)>,
+) -> f32 {
+    let tile_size = TILE_SIZE as f32;
+    let mut occluded = 0.0;
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            let neighbour = (tx + dx, ty + dy);
+            if (dx == 0 && dy == 0) || Some(neighbour) == ignore || !map.is_blocking_at(neighbour) {
+                continue;
+            }
+
+            // Distance from the point to the closest spot on the neighbouring tile
+            let min = (
+                neighbour.0 as f32 * tile_size,
+                neighbour.1 as f32 * tile_size,
+            );
+            let nearest = (
+                x.max(min.0).min(min.0 + tile_size),
+                y.max(min.1).min(min.1 + tile_size),
+            );
+            let distance = length(x - nearest.0, y - nearest.1);
+            occluded += (1.0 - distance / OCCLUSION_RADIUS).max(0.0);
+        }
+    }
+
+    (1.0 - occluded * OCCLUSION_STRENGTH).max(MIN_OCCLUSION)
+}
+
+// No libm hypot
+fn length(x: f32, y: f32) -> f32 {
+    (x * x + y * y).sqrt()
+}
+
+// The first and last tiles a light's radius could touch
+fn reach_bounds(light: &Light, width: i32, height: i32) -> ((i32, i32), (i32, i32)) {
+    let reach = light.radius.ceil() as i32;
+    let min = (
+        ((light.x - reach) / TILE_SIZE).max(0),
+        ((light.y - reach) / TILE_SIZE).max(0),
+    );
+    let max = (
+        ((light.x + reach) / TILE_SIZE).min(width - 1),
+        ((light.y + reach) / TILE_SIZE).min(height - 1),
+    );
+    (min, max)
+}
+
+// For every tile, which lights could possibly reach it
+fn lights_by_tile(map: &Map) -> Vec<Vec<usize>> {
+    let (width, height) = (map.width(), map.height());
+    let mut candidates = vec![Vec::new(); (width * height) as usize];
+
+    for (l, light) in map.lights().iter().enumerate() {
+        let (min, max) = reach_bounds(light, width, height);
+        for y in min.1..=max.1 {
+            for x in min.0..=max.0 {
+                candidates[(width * y + x) as usize].push(l);
+            }
+        }
+    }
+
+    candidates
+}
+
+// Whether the line between two points goes through the given tile, a hair generous
+fn crosses_tile((ax, ay): (f32, f32), (bx, by): (f32, f32), (tx, ty): (i32, i32)) -> bool {
+    let tile_size = TILE_SIZE as f32;
+    let min = (tx as f32 * tile_size - 0.01, ty as f32 * tile_size - 0.01);
+    let max = (min.0 + tile_size + 0.02, min.1 + tile_size + 0.02);
+
+    let mut enter = 0.0f32;
+    let mut exit = 1.0f32;
+    for &(start, delta, min, max) in &[(ax, bx - ax, min.0, max.0), (ay, by - ay, min.1, max.1)] {
+        if delta.abs() < std::f32::EPSILON {
+            if start < min || start > max {
+                return false;
+            }
+            continue;
+        }
+
+        let (t0, t1) = ((min - start) / delta, (max - start) / delta);
+        enter = enter.max(t0.min(t1));
+        exit = exit.min(t0.max(t1));
+        if enter > exit {
+            return false;
+        }
+    }
+
+    true
+}
+
+// Walks through a cached lightmap's bytes
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.at..self.at + n)
+            .ok_or_else(|| "Lightmap cache ends too early".to_owned())?;
+        self.at += n;
+        Ok(bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
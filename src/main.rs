@@ -1,26 +1,56 @@
-use bevy::prelude::{App, Query};
+use bevy::prelude::{App, Query, Time};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use std::collections::HashSet;
 
+mod band;
 mod base_plugin;
+mod bench;
+mod camera;
+mod controls;
+mod dither;
+mod fixed;
+mod fog;
 mod font;
 mod game;
 mod game_plugin;
+mod light;
+mod lightmap;
+mod palette;
+mod profile;
 mod raycaster;
+mod rays;
+mod settings;
 mod texture;
+mod tileset;
 mod util;
 
 use base_plugin::BasePlugin;
+use camera::{Camera, Layout, Target, DEFAULT_FOV, MAX_PLAYERS};
+use fixed::Trig;
+use fog::Fog;
 use game::Game;
-use game_plugin::{GamePlugin, Player, Position};
+use game_plugin::{GamePlugin, Player, Position, SplitScreen};
+use light::{Light, LightSource, MAX_DYNAMIC_LIGHTS};
+use palette::{Colormap, Palette};
 use raycaster::raycast;
 use raycaster::Map;
+use rays::RayTable;
+use settings::{Filter, Math, Pipeline, RenderSettings, Spacing};
 use texture::Texture;
+use tileset::Tileset;
 
 pub const TILE_SIZE: i32 = 12;
 
+// Size of each texture in the wall tileset
+const WALL_TEXTURE_SIZE: u32 = 128;
+
 const resulting_resolution: (i32, i32) = (320, 200);
 const actual_resolution: (u32, u32) = (1080, 768);
+// How many texels across the textures drawn by map cameras are
+const MONITOR_SIZE: i32 = 64;
+// Height of a line of HUD text, which is drawn 18px high
+const HUD_LINE_HEIGHT: i32 = 22;
 
 #[derive(Debug)]
 pub struct MouseMotion {
@@ -44,41 +74,46 @@ impl MouseMotion {
     }
 }
 
+// Every key that's held down, so players sharing the keyboard can all move at once
 pub struct Keypress {
-    val: Option<Keycode>,
+    held: HashSet<Keycode>,
 }
 
 impl Keypress {
     pub fn new() -> Keypress {
-        Keypress { val: None }
+        Keypress {
+            held: HashSet::new(),
+        }
     }
 
     fn set(&mut self, ch: Keycode) {
-        self.val = Some(ch);
+        self.held.insert(ch);
     }
 
     fn is(&self, ch: Keycode) -> bool {
-        if let Some(cur_ch) = &self.val {
-            return *cur_ch == ch;
-        }
-        false
+        self.held.contains(&ch)
     }
 
-    fn clear(&mut self) {
-        self.val = None;
+    fn release(&mut self, ch: Keycode) {
+        self.held.remove(&ch);
     }
 }
 
 struct GameState {
     bevy: App,
-    wall_texture: Texture,
+    walls: Tileset,
     floor_texture: Texture,
     fps: f64,
     map: Map,
+    render_settings: RenderSettings,
+    colormap: Colormap,
+    trig: Trig,
+    // Ray tables for each camera, in the order they're drawn
+    rays: Vec<RayTable>,
 }
 
 impl GameState {
-    pub fn new() -> Result<GameState, String> {
+    pub fn new(split: SplitScreen) -> Result<GameState, String> {
         //time::set_timestep(context, time::Timestep::Variable);
         let keypress = Keypress::new();
         let mouse_motion = MouseMotion::new();
@@ -88,6 +123,7 @@ impl GameState {
                 .add_plugin(GamePlugin)
                 .add_resource(keypress)
                 .add_resource(mouse_motion)
+                .add_resource(split)
                 .app,
             App::default(),
         );
@@ -99,24 +135,121 @@ impl GameState {
             &mut bevy.resources,
         );
 
-        let wall_texture = Texture::new("assets/stone_wall_b.png");
-        let floor_texture = Texture::new("assets/stone_floor_c.png");
         //let canvas = Canvas::new(context, resulting_resolution.0, resulting_resolution.1).unwrap();
 
+        let map = Map::load("assets/maps/default.map")?;
+        let (mut walls, floor_texture, colormap) = load_textures(map.fog())?;
+
+        // Every live texture gets a camera drawing into it
+        for camera in map.cameras() {
+            walls.bind(
+                camera.texture,
+                Texture::blank(MONITOR_SIZE as u32, MONITOR_SIZE as u32),
+            );
+            bevy.world.spawn((
+                Position::new(camera.x, camera.y),
+                game_plugin::Rotation::new(camera.degrees),
+                Camera::on_wall(DEFAULT_FOV, MONITOR_SIZE, camera.texture),
+            ));
+        }
+        map.check_wall_textures(walls.tiles().len())?;
+
         Ok(GameState {
             bevy,
-            wall_texture,
+            walls,
             floor_texture,
             fps: 0.0,
-            map: Map::new(),
+            map,
+            render_settings: RenderSettings::default(),
+            colormap,
+            trig: Trig::new(),
+            rays: Vec::new(),
         })
     }
+
+    // Collects the lights carried by entities this frame, keeping the ones closest to the player
+    fn dynamic_lights(&self) -> Vec<Light> {
+        let viewer = self
+            .bevy
+            .world
+            .query::<(&Position, &Player)>()
+            .iter()
+            .next()
+            .map(|(position, _)| (position.x, position.y));
+
+        let mut lights: Vec<Light> = self
+            .bevy
+            .world
+            .query::<(&Position, &LightSource)>()
+            .iter()
+            .map(|(position, source)| source.at(position.x, position.y))
+            .collect();
+
+        if let Some((vx, vy)) = viewer {
+            // Squared, no libm hypot
+            let distance = |light: &Light| {
+                let (dx, dy) = (light.x as f32 - vx, light.y as f32 - vy);
+                dx * dx + dy * dy
+            };
+            lights.sort_by(|a, b| {
+                distance(a)
+                    .partial_cmp(&distance(b))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+        }
+
+        lights.truncate(MAX_DYNAMIC_LIGHTS);
+        lights
+    }
+}
+
+// The ray table of the `i`th camera, worked out again if the camera changed since last frame
+fn rays_for<'a>(
+    rays: &'a mut Vec<RayTable>,
+    i: usize,
+    camera: &Camera,
+    settings: &RenderSettings,
+    trig: &Trig,
+) -> &'a RayTable {
+    let (width, fov) = (camera.viewport.width, camera.zoomed_fov());
+    if i < rays.len() {
+        rays[i].update(width, fov, settings.spacing, settings.math, trig);
+    } else {
+        rays.push(RayTable::new(
+            width,
+            fov,
+            settings.spacing,
+            settings.math,
+            trig,
+        ));
+    }
+    &rays[i]
+}
+
+// Loads the wall tileset and floor texture, along with the
+// colormap the indexed pipeline needs to draw them
+fn load_textures(fog: &Fog) -> Result<(Tileset, Texture, Colormap), String> {
+    let mut walls = Tileset::slice("assets/walls.png", WALL_TEXTURE_SIZE, WALL_TEXTURE_SIZE)
+        .map_err(|e| e.to_string())?;
+    let mut floor_texture = Texture::new("assets/stone_floor_c.png").map_err(|e| e.to_string())?;
+
+    let mut textures: Vec<&Texture> = walls.tiles().iter().collect();
+    textures.push(&floor_texture);
+    let palette = Palette::from_textures(&textures, fog);
+    for wall in walls.tiles_mut() {
+        wall.index_with(&palette);
+    }
+    floor_texture.index_with(&palette);
+
+    Ok((walls, floor_texture, Colormap::new(palette, fog)))
 }
 
 pub trait State {
     fn update(&mut self) -> Result<(), String>;
     fn draw(&mut self, buf: &mut [u8]) -> Result<(), String>;
     fn event(&mut self, event: Event) -> Result<(), String>;
+    // Text to draw over the frame, and where on the screen
+    fn hud(&self) -> Vec<(i32, i32, String)>;
 }
 
 impl State for GameState {
@@ -128,6 +261,11 @@ impl State for GameState {
             mm.clear();
         }
 
+        {
+            let time = self.bevy.resources.get::<Time>().unwrap();
+            self.map.animate_lights(time.seconds_since_startup);
+        }
+
         /*
         for p in self.bevy.world.query::<&Position>().iter() {
             println!(
@@ -140,7 +278,8 @@ impl State for GameState {
     }
 
     fn draw(&mut self, buf: &mut [u8]) -> Result<(), String> {
-        let fov = 66;
+        let lights = self.dynamic_lights();
+        self.map.set_dynamic_lights(&lights);
 
         /*
         let fps = graphics::text::Text::new(
@@ -152,21 +291,67 @@ impl State for GameState {
         graphics::clear(ctx, graphics::Color::rgb(0.1568, 0.1746, 0.1568));
         */
 
-        for (position, _, rotation) in self
+        // Monitors first, so the screen shows what their cameras see this frame
+        for (i, (position, rotation, camera)) in self
+            .bevy
+            .world
+            .query::<(&Position, &game_plugin::Rotation, &Camera)>()
+            .iter()
+            .enumerate()
+        {
+            let id = match camera.target {
+                Target::Wall(id) => id,
+                Target::Screen => continue,
+            };
+
+            let size = (camera.viewport.width, camera.viewport.height);
+            let mut pixels = [0, 0, 0, 0xff].repeat((size.0 * size.1) as usize);
+            raycast(
+                size,
+                camera,
+                rays_for(&mut self.rays, i, camera, &self.render_settings, &self.trig),
+                position,
+                rotation,
+                &mut pixels,
+                &self.walls,
+                &self.floor_texture,
+                &self.map,
+                &self.render_settings,
+                &self.colormap,
+                &self.trig,
+            )?;
+
+            let texture = &mut self.walls.tiles_mut()[id];
+            texture.update(&pixels).map_err(|e| e.to_string())?;
+            if self.render_settings.pipeline == Pipeline::Indexed {
+                texture.index_with(self.colormap.palette());
+            }
+        }
+
+        for (i, (position, rotation, camera)) in self
             .bevy
             .world
-            .query::<(&Position, &Player, &game_plugin::Rotation)>()
+            .query::<(&Position, &game_plugin::Rotation, &Camera)>()
             .iter()
+            .enumerate()
         {
+            if camera.target != Target::Screen {
+                continue;
+            }
+
             raycast(
                 resulting_resolution,
-                fov,
+                camera,
+                rays_for(&mut self.rays, i, camera, &self.render_settings, &self.trig),
                 position,
                 rotation,
                 buf,
-                &self.wall_texture,
+                &self.walls,
                 &self.floor_texture,
                 &self.map,
+                &self.render_settings,
+                &self.colormap,
+                &self.trig,
             )
             .expect("Failed raycasting");
 
@@ -194,6 +379,28 @@ impl State for GameState {
         Ok(())
     }
 
+    // Each player's number in the bottom left of their view, with how far they're zoomed in
+    fn hud(&self) -> Vec<(i32, i32, String)> {
+        self.bevy
+            .world
+            .query::<(&Player, &Camera)>()
+            .iter()
+            .map(|(player, camera)| {
+                let label = if camera.zoom > 1.0 {
+                    format!("P{} x{:.1}", player.number + 1, camera.zoom)
+                } else {
+                    format!("P{}", player.number + 1)
+                };
+                let viewport = camera.viewport;
+                (
+                    viewport.x + 2,
+                    viewport.y + viewport.height - HUD_LINE_HEIGHT,
+                    label,
+                )
+            })
+            .collect()
+    }
+
     fn event(&mut self, event: Event) -> Result<(), String> {
         match event {
             Event::KeyDown { keycode, .. } => {
@@ -205,7 +412,7 @@ impl State for GameState {
             Event::KeyUp { keycode, .. } => {
                 if let Some(kc) = keycode {
                     let mut kp = self.bevy.resources.get_mut::<Keypress>().unwrap();
-                    kp.clear();
+                    kp.release(kc);
                 }
             }
             Event::MouseMotion { xrel, yrel, .. } => {
@@ -219,10 +426,124 @@ impl State for GameState {
     }
 }
 
+// Bakes the lights of every map in the directory that doesn't have them cached already
+fn bake_maps(dir: &str) -> Result<(), String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir, e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().map_or(true, |ext| ext != "map") {
+            continue;
+        }
+
+        let path = path.to_string_lossy();
+        Map::load(&path)?;
+        println!("{} is baked", path);
+    }
+
+    Ok(())
+}
+
+// Packs separate textures into one wall tileset, 8 to a row, in the order given
+fn pack_textures(out: &str, paths: &[String]) -> Result<(), String> {
+    if paths.is_empty() {
+        return Err("Nothing to pack".to_owned());
+    }
+
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    let atlas = Tileset::pack(&paths, WALL_TEXTURE_SIZE, WALL_TEXTURE_SIZE, 8)
+        .map_err(|e| e.to_string())?;
+    atlas.save(out).map_err(|e| format!("{}: {}", out, e))?;
+
+    for (id, path) in paths.iter().enumerate() {
+        println!("{} is texture {}", path, id);
+    }
+    Ok(())
+}
+
+// Render settings and field of view for a benchmark run, from flags like --indexed or --fov=90
+fn bench_settings(flags: &[String]) -> Result<(RenderSettings, f32), String> {
+    let mut settings = RenderSettings::default();
+    let mut fov = DEFAULT_FOV;
+    for flag in flags {
+        match flag.as_str() {
+            "--indexed" => settings.pipeline = Pipeline::Indexed,
+            "--bilinear" => settings.filter = Filter::Bilinear,
+            "--fixed" => settings.math = Math::Fixed,
+            "--no-mipmaps" => settings.mipmaps = false,
+            "--even" => settings.spacing = Spacing::Even,
+            "--no-reflections" => settings.reflections = false,
+            _ if flag.starts_with("--fov=") => {
+                fov = flag["--fov=".len()..]
+                    .parse()
+                    .map_err(|_| format!("Bad field of view in {}", flag))?;
+            }
+            _ if flag.starts_with("--threads=") => {
+                settings.threads = flag["--threads=".len()..]
+                    .parse()
+                    .map_err(|_| format!("Bad thread count in {}", flag))?;
+            }
+            _ => return Err(format!("Unknown flag {}", flag)),
+        }
+    }
+    Ok((settings, fov))
+}
+
+// How many players share the screen, from --players=2 and --stacked
+fn split_screen(flags: &[String]) -> Result<SplitScreen, String> {
+    let mut split = SplitScreen {
+        players: 1,
+        layout: Layout::SideBySide,
+    };
+    for flag in flags {
+        match flag.as_str() {
+            "--stacked" => split.layout = Layout::Stacked,
+            _ if flag.starts_with("--players=") => {
+                split.players = flag["--players=".len()..]
+                    .parse()
+                    .ok()
+                    .filter(|players| (1..=MAX_PLAYERS).contains(players))
+                    .ok_or_else(|| {
+                        format!("Between 1 and {} players, not {}", MAX_PLAYERS, flag)
+                    })?;
+            }
+            _ => return Err(format!("Unknown flag {}", flag)),
+        }
+    }
+    Ok(split)
+}
+
 fn main() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("bake") => return bake_maps(args.get(2).map_or("assets/maps", String::as_str)),
+        Some("pack") => {
+            let out = args
+                .get(2)
+                .ok_or("Usage: raycaster pack <atlas.png> <textures...>")?;
+            return pack_textures(out, &args[3..]);
+        }
+        Some("bench") => {
+            let (flags, args): (Vec<String>, Vec<String>) =
+                args[2..].iter().cloned().partition(|a| a.starts_with("--"));
+            let map = args
+                .get(0)
+                .map_or("assets/maps/default.map", String::as_str);
+            let frames = match args.get(1) {
+                Some(frames) => frames
+                    .parse()
+                    .map_err(|_| format!("{} is not a number of frames", frames))?,
+                None => 600,
+            };
+            let (settings, fov) = bench_settings(&flags)?;
+            return bench::run(map, frames, settings, fov);
+        }
+        _ => {}
+    }
+
     let mut game = Game::new("tetra + bevy", actual_resolution.0, actual_resolution.1)?;
 
-    game.run(GameState::new)?;
+    let split = split_screen(&args[1..])?;
+    game.run(|| GameState::new(split))?;
 
     //let mut texture = texture_creator.load_texture("assets/stone_wall.png")?;
     //let mut floor_texture = texture_creator.load_texture("assets/stone_floor.png")?;
@@ -0,0 +1,190 @@
+use crate::fog::Fog;
+use crate::texture::Texture;
+
+// How many steps of brightness the colormaps have, from black to over-bright
+pub const LIGHT_LEVELS: usize = 32;
+// Brightness multiplier the last light level stands for
+const BRIGHTEST: f32 = 1.5;
+// How many steps of fog the colormaps have, from clear to fully fogged
+pub const FOG_LEVELS: usize = 16;
+
+// The shades every texel gets baked into when building the palette,
+// so there's something to pick from when textures are darkened
+const SHADES: [f32; 5] = [1.25, 1.0, 0.6, 0.3, 0.12];
+
+pub struct Palette {
+    colors: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    // Picks 256 colors that fit the given textures (at every shade, and fogged) the best.
+    // Index 0 is always black and index 1 always the fog color.
+    pub fn from_textures(textures: &[&Texture], fog: &Fog) -> Palette {
+        let mut pixels = Vec::new();
+        for texture in textures {
+            for y in 0..texture.height() {
+                for x in 0..texture.width() {
+                    let (r, g, b) = texture.color_at(x, y);
+                    for shade in SHADES.iter() {
+                        let c = |v: u8| (v as f32 * shade).min(255.0) as u8;
+                        let shaded = (c(r), c(g), c(b));
+                        pixels.push([shaded.0, shaded.1, shaded.2]);
+
+                        let (fr, fg, fb) = fog.apply(shaded, 0.5);
+                        pixels.push([fr, fg, fb]);
+                    }
+                }
+            }
+        }
+
+        let mut colors = vec![[0, 0, 0], fog.color];
+        colors.extend(median_cut(pixels, 256 - colors.len()));
+        colors.resize(256, [0, 0, 0]);
+
+        Palette { colors }
+    }
+
+    pub fn color(&self, index: u8) -> [u8; 3] {
+        self.colors[index as usize]
+    }
+
+    pub fn nearest(&self, [r, g, b]: [u8; 3]) -> u8 {
+        let mut best = (0, u32::MAX);
+        for (i, [pr, pg, pb]) in self.colors.iter().enumerate() {
+            let d = |a: u8, b: u8| {
+                let d = a as i32 - b as i32;
+                (d * d) as u32
+            };
+            let distance = d(r, *pr) + d(g, *pg) + d(b, *pb);
+            if distance < best.1 {
+                best = (i, distance);
+            }
+        }
+
+        best.0 as u8
+    }
+}
+
+// Precomputed lighting for an indexed palette, like Doom's COLORMAP: for every
+// light level (and fog level), which palette index each palette index turns into
+pub struct Colormap {
+    palette: Palette,
+    levels: Vec<[u8; 256]>,
+    fog: Vec<[u8; 256]>,
+}
+
+impl Colormap {
+    pub fn new(palette: Palette, fog: &Fog) -> Colormap {
+        let levels = (0..LIGHT_LEVELS)
+            .map(|level| {
+                let mult = level as f32 / (LIGHT_LEVELS - 1) as f32 * BRIGHTEST;
+                let mut table = [0; 256];
+                for (i, entry) in table.iter_mut().enumerate() {
+                    let [r, g, b] = palette.color(i as u8);
+                    let c = |v: u8| (v as f32 * mult).min(255.0) as u8;
+                    *entry = palette.nearest([c(r), c(g), c(b)]);
+                }
+                table
+            })
+            .collect();
+
+        let fog = (0..FOG_LEVELS)
+            .map(|level| {
+                let amount = level as f32 / (FOG_LEVELS - 1) as f32;
+                let mut table = [0; 256];
+                for (i, entry) in table.iter_mut().enumerate() {
+                    let [r, g, b] = palette.color(i as u8);
+                    let (r, g, b) = fog.apply((r, g, b), amount);
+                    *entry = palette.nearest([r, g, b]);
+                }
+                table
+            })
+            .collect();
+
+        Colormap {
+            palette,
+            levels,
+            fog,
+        }
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    // Which light level is closest to the given (colored) brightness.
+    // Colormaps can't tint, so colored light only counts for how bright it is.
+    pub fn light_level(&self, [r, g, b]: [f32; 3]) -> usize {
+        let luma = r * 0.299 + g * 0.587 + b * 0.114;
+        let level = (luma / BRIGHTEST * (LIGHT_LEVELS - 1) as f32).round();
+        (level.max(0.0) as usize).min(LIGHT_LEVELS - 1)
+    }
+
+    pub fn fog_level(&self, amount: f32) -> usize {
+        let level = (amount * (FOG_LEVELS - 1) as f32).round();
+        (level.max(0.0) as usize).min(FOG_LEVELS - 1)
+    }
+
+    pub fn shade(&self, index: u8, light_level: usize, fog_level: usize) -> [u8; 3] {
+        let lit = self.levels[light_level][index as usize];
+        self.palette.color(self.fog[fog_level][lit as usize])
+    }
+}
+
+// Splits the colors into boxes along their widest channel until there are
+// `count` of them, and averages each box into one color
+fn median_cut(pixels: Vec<[u8; 3]>, count: usize) -> Vec<[u8; 3]> {
+    let mut boxes = vec![pixels];
+
+    while boxes.len() < count {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| (i, widest_channel(b)))
+            .max_by_key(|(_, (_, range))| *range);
+
+        let (i, channel) = match widest {
+            Some((i, (channel, range))) if range > 0 => (i, channel),
+            _ => break,
+        };
+
+        let mut colors = boxes.swap_remove(i);
+        colors.sort_unstable_by_key(|c| c[channel]);
+        let upper = colors.split_off(colors.len() / 2);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes
+        .iter()
+        .filter(|b| !b.is_empty())
+        .map(|b| {
+            let mut sum = [0u64; 3];
+            for c in b {
+                for k in 0..3 {
+                    sum[k] += c[k] as u64;
+                }
+            }
+            let n = b.len() as u64;
+            [(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8]
+        })
+        .collect()
+}
+
+// The channel the colors are most spread out on, and by how much
+fn widest_channel(colors: &[[u8; 3]]) -> (usize, u8) {
+    let mut min = [255u8; 3];
+    let mut max = [0u8; 3];
+    for c in colors {
+        for k in 0..3 {
+            min[k] = min[k].min(c[k]);
+            max[k] = max[k].max(c[k]);
+        }
+    }
+
+    (0..3)
+        .map(|k| (k, max[k] - min[k]))
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Walls,
+    Floors,
+    // Animating the baked lights and working out what the moving ones can see.
+    // Looking up how lit each pixel is happens while it's drawn, so that part
+    // counts towards walls and floors instead.
+    Lighting,
+    Text,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [Phase::Walls, Phase::Floors, Phase::Lighting, Phase::Text];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Walls => "walls",
+            Phase::Floors => "floors",
+            Phase::Lighting => "lighting",
+            Phase::Text => "text",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+// How long each phase of a frame took
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PhaseTimes {
+    times: [Duration; 4],
+}
+
+impl PhaseTimes {
+    pub fn get(&self, phase: Phase) -> Duration {
+        self.times[phase.index()]
+    }
+
+    pub fn add(&mut self, phase: Phase, time: Duration) {
+        self.times[phase.index()] += time;
+    }
+
+    // Runs `f`, adding how long it took to the phase
+    pub fn time<T, F: FnOnce() -> T>(&mut self, phase: Phase, f: F) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(phase, start.elapsed());
+        result
+    }
+
+    // For things that ran at the same time, like the bands of a frame,
+    // only the slowest one holds the frame back
+    pub fn slowest(&self, other: &PhaseTimes) -> PhaseTimes {
+        let mut times = self.times;
+        for (time, other) in times.iter_mut().zip(other.times.iter()) {
+            *time = (*time).max(*other);
+        }
+        PhaseTimes { times }
+    }
+}
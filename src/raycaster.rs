@@ -1,87 +1,164 @@
-use rand::{SeedableRng, Rng};
-
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use rayon::prelude::*;
+
+use crate::band::Band;
+use crate::camera::Camera;
+use crate::dither::Dither;
+use crate::fixed::{self, Trig};
+use crate::fog::{Fog, FogMode};
 use crate::game_plugin::{Position, Rotation};
-use crate::texture::{Drawable, Texture};
+use crate::light::{DynamicLight, Light};
+use crate::lightmap::{Face, Lightmap, BAKE_VERSION, DEFAULT_DENSITY, MAX_DENSITY};
+use crate::palette::Colormap;
+use crate::profile::{Phase, PhaseTimes};
+use crate::rays::{Column, RayTable};
+use crate::settings::{Filter, Math, Pipeline, RenderSettings};
+use crate::texture::{BaseShade, Drawable, Texture};
+use crate::tileset::Tileset;
 use crate::util;
 
 use crate::TILE_SIZE;
 
+// Draws what a camera at `position` looking along `rotation` sees into its viewport
 pub fn raycast(
-    projection_plane: (i32, i32),
-    fov: i32,
+    screen: (i32, i32),
+    camera: &Camera,
+    rays: &RayTable,
     position: &Position,
     rotation: &Rotation,
     pixels: &mut [u8],
-    wall_texture: &Texture,
+    walls: &Tileset,
     floor_texture: &Texture,
     map: &Map,
-) -> Result<(), String> {
-    let half_fov = Rotation::new(fov as f32 / 2.0);
-    let fov = Rotation::new(fov as f32);
-    
-    let mut rng = rand::rngs::SmallRng::from_entropy();
-
-    // using the formula tan(angle) = opposite / adjacent
-    // We know the angle, because that's FOV/2
-    // We know opposite, because that's projection's plane width / 2
-    let distance_to_plane = (projection_plane.0 / 2) as f32 / half_fov.tan();
-
-    // The angle increment between rays is known by the fov. ie, how many steps would you need to fit the plane.
-    let degrees_per_iteration = fov.degrees() / projection_plane.0 as f32;
-
-    // The starting angle is the viewing angle rotated minus half the fov.
-    // \  |  /
-    //  \ | /
-    //  º\|/
-    //  º p--------
-    //  ºººº
-    let mut ray_rotation = rotation.rotated(-half_fov.degrees());
+    settings: &RenderSettings,
+    colormap: &Colormap,
+    trig: &Trig,
+) -> Result<PhaseTimes, String> {
+    let viewport = camera.viewport;
+    if !viewport.fits(screen) {
+        return Err(format!("{:?} doesn't fit a {:?} screen", viewport, screen));
+    }
 
-    let tile_size = TILE_SIZE as f32;
-    for x in 0..projection_plane.0 {
-        let horizontal_distance = if ray_rotation.is_straight_horizontal() {
-            (IntersectionPoint::default(), f32::MAX)
-        } else {
-            look_for_horizontal(&ray_rotation, position, &map)?
-        };
-        let vertical_distance = if ray_rotation.is_straight_vertical() {
-            (IntersectionPoint::default(), f32::MAX)
-        } else {
-            look_for_vertical(&ray_rotation, position, &map)?
-        };
+    let projection_plane = (viewport.width, viewport.height);
+    if rays.width() != projection_plane.0 {
+        return Err(format!(
+            "Rays are for {} columns, the viewport has {}",
+            rays.width(),
+            projection_plane.0
+        ));
+    }
 
-        // Drawing some debug lines for the rays
-        /*
-        canvas.set_draw_color((20, 50, 20));
-        let ray_dir = ray_rotation.direction() * 5.0;
-        let some_distance_away = (position.x + ray_dir.x, position.y + ray_dir.y);
-
-        canvas.draw_line(
-            (position.x.floor() as i32, position.y.floor() as i32),
-            (
-                some_distance_away.0.floor() as i32,
-                some_distance_away.1.floor() as i32,
-            ),
-        )?;
-        */
+    // Looking up or down just slides the horizon, the walls stay upright
+    let tan_pitch = match settings.math {
+        Math::Float => camera.pitch().to_radians().tan(),
+        Math::Fixed => fixed::to_f32(trig.tan(fixed::angle(camera.pitch()))),
+    };
+    let horizon = projection_plane.1 / 2 + (tan_pitch * rays.distance_to_plane()).round() as i32;
 
-        // Kay, draw the walls now if we hit something
-        let ((intersection, closest_hit), side) = if horizontal_distance.1 < vertical_distance.1 {
-            (horizontal_distance, 'h')
-        } else {
-            (vertical_distance, 'v')
-        };
+    // Every column is independent, so the viewport is split into bands of columns that
+    // are drawn on their own threads and copied back when they're all done
+    let threads = if settings.threads == 0 {
+        rayon::current_num_threads()
+    } else {
+        settings.threads
+    };
+    let mut bands = Band::split(projection_plane.0, projection_plane.1, threads);
+    for band in bands.iter_mut() {
+        band.read_from(pixels, screen.0, (viewport.x, viewport.y));
+    }
+
+    let times = bands
+        .par_iter_mut()
+        .map(|band| {
+            raycast_band(
+                projection_plane,
+                horizon,
+                rays,
+                position,
+                rotation,
+                band,
+                walls,
+                floor_texture,
+                map,
+                settings,
+                colormap,
+                trig,
+            )
+        })
+        .collect::<Result<Vec<PhaseTimes>, String>>()?;
+
+    for band in bands.iter() {
+        band.write_to(pixels, screen.0, (viewport.x, viewport.y));
+    }
 
-        if closest_hit != f32::MAX {
-            let distance_to_wall =
-                closest_hit * (ray_rotation.radians() - rotation.radians()).cos();
-            let projected_height =
-                (tile_size / distance_to_wall * distance_to_plane).floor() as i32;
+    Ok(times
+        .iter()
+        .fold(PhaseTimes::default(), |slowest, band| slowest.slowest(band)))
+}
 
-            let mid_point = projection_plane.1 / 2;
+fn raycast_band(
+    projection_plane: (i32, i32),
+    horizon: i32,
+    rays: &RayTable,
+    position: &Position,
+    rotation: &Rotation,
+    pixels: &mut Band,
+    walls: &Tileset,
+    floor_texture: &Texture,
+    map: &Map,
+    settings: &RenderSettings,
+    colormap: &Colormap,
+    trig: &Trig,
+) -> Result<PhaseTimes, String> {
+    let mut times = PhaseTimes::default();
+    let walls_start = Instant::now();
+
+    // The noise only depends on where a pixel is, so it comes out the
+    // same however the screen is split
+    let dither = Dither::new(settings.dither);
+
+    let distance_to_plane = rays.distance_to_plane();
+    let view = fixed::angle(rotation.degrees());
+    let position_fixed = (fixed::from_f32(position.x), fixed::from_f32(position.y));
+
+    // Where each column's wall ends, floors and ceilings are drawn after all the walls
+    let mut floor_columns = Vec::with_capacity((pixels.right() - pixels.left()) as usize);
+    // Mirrors that get drawn over their reflections, and how much of them shows
+    let mut mirrors = Vec::new();
+    let reflections = settings.reflections && settings.pipeline == Pipeline::TrueColor;
+
+    for x in pixels.left()..pixels.right() {
+        // Each column's angle from the view comes from the ray table, so a
+        // column comes out the same whichever band it's in.
+        // \  |  /
+        //  \ | /
+        //  º\|/
+        //  º p--------
+        //  ºººº
+        let column = rays.column(x);
+        let hit = match settings.math {
+            Math::Float => cast_float(column, rotation, position, map)?,
+            Math::Fixed => cast_fixed(column, view, position_fixed, map, trig),
+        };
 
-            let wall_bottom = mid_point + projected_height / 2;
-            let wall_top = mid_point - projected_height / 2;
+        // Kay, draw the walls now if we hit something
+        if let Some(hit) = hit {
+            let jitter = dither.at(x, projection_plane.1 / 2);
+            let wall = WallStrip::new(
+                walls.get(map.wall_texture_at(hit.tile)),
+                (hit.x, hit.y, hit.side),
+                hit.perpendicular,
+                hit.distance,
+                jitter,
+                distance_to_plane,
+                horizon,
+                map,
+                settings,
+            );
 
             // Draw fill color of walls
             /*
@@ -91,70 +168,487 @@ pub fn raycast(
             canvas.draw_line((x, wall_top), (x, wall_bottom - 2))?;
             */
 
-            // Draw wall texture
-            let wall_x = if side == 'h' {
-                intersection.x
-            } else {
-                intersection.y
+            let strength = map.mirror_at(hit.tile).filter(|_| reflections);
+            let bounce = match (strength, settings.math) {
+                (None, _) => None,
+                (Some(_), Math::Float) => {
+                    bounce_float(&hit, &rotation.rotated(column.degrees), map)?
+                }
+                (Some(_), Math::Fixed) => bounce_fixed(&hit, view + column.steps, map, trig),
             };
-            let tex_x = ((wall_x / tile_size).fract() * wall_texture.width() as f32) as i32;
 
-            let dst_to_light = map.distance_to_light(intersection.x, intersection.y, Some(&mut rng), side);
+            match (strength, bounce) {
+                // What the mirror reflects gets drawn as if it was behind it, and the
+                // mirror itself goes over it once the floors are in
+                (Some(strength), Some(bounce)) => {
+                    let distance = hit.distance + bounce.distance;
+                    let reflected = WallStrip::new(
+                        walls.get(map.wall_texture_at(bounce.tile)),
+                        (bounce.x, bounce.y, bounce.side),
+                        distance * column.cos,
+                        distance,
+                        jitter,
+                        distance_to_plane,
+                        horizon,
+                        map,
+                        settings,
+                    );
+                    reflected.draw(x, pixels, map, settings, colormap);
+
+                    let mirror = Mirror {
+                        side: hit.side,
+                        line: if hit.side == 'h' { hit.y } else { hit.x },
+                        distance: hit.perpendicular,
+                    };
+                    floor_columns.push(FloorColumn::new(
+                        x,
+                        reflected,
+                        distance * column.cos,
+                        &hit,
+                        Some(mirror),
+                    ));
+                    mirrors.push((x, wall, 1.0 - strength));
+                }
+                _ => {
+                    wall.draw(x, pixels, map, settings, colormap);
+                    floor_columns.push(FloorColumn::new(x, wall, hit.perpendicular, &hit, None));
+                }
+            }
+        }
+    }
 
-            let light_mult = light_intensity(dst_to_light);
+    times.add(Phase::Walls, walls_start.elapsed());
+
+    times.time(Phase::Floors, || {
+        floorcast(
+            &floor_columns,
+            position,
+            distance_to_plane,
+            projection_plane,
+            horizon,
+            pixels,
+            floor_texture,
+            &map,
+            &dither,
+            settings,
+            colormap,
+        )
+    })?;
+
+    times.time(Phase::Walls, || {
+        for (x, mirror, opacity) in &mirrors {
+            mirror.draw_over(*x, pixels, map, *opacity);
+        }
+    });
+
+    Ok(times)
+}
+
+// A column of wall, ready to draw
+struct WallStrip<'a> {
+    // Already the right mip level
+    texture: &'a Texture,
+    tex_u: f32,
+    top: i32,
+    bottom: i32,
+    // The light on the wall, and how much of it is left at its base
+    mult: [f32; 3],
+    occlusion: f32,
+    fog_amount: f32,
+}
 
-            let mult = 1. / distance_to_wall + light_mult;
+// How far up from the floor walls get darker in nooks and corners, as a part of their height
+const BASE_SHADE_FRACTION: f32 = 0.5;
+
+impl<'a> WallStrip<'a> {
+    // `hit` is where on the map the wall was hit and from which side, `perpendicular`
+    // how far straight ahead of the camera that is and `distance` how far along the ray
+    fn new(
+        texture: &'a Texture,
+        (x, y, side): (f32, f32, char),
+        perpendicular: f32,
+        distance: f32,
+        jitter: (f32, f32),
+        distance_to_plane: f32,
+        horizon: i32,
+        map: &Map,
+        settings: &RenderSettings,
+    ) -> WallStrip<'a> {
+        let tile_size = TILE_SIZE as f32;
+        let projected_height = (tile_size / perpendicular * distance_to_plane).floor() as i32;
 
-            // So dark we don't need to copy anything
-            if mult > 0.00 {
-                wall_texture.draw_strip_at_ex(
+        // How many texels each pixel of the strip covers
+        let texture = if settings.mipmaps {
+            texture.mip(texture.height() as f32 / projected_height.max(1) as f32)
+        } else {
+            texture
+        };
+        let wall_x = if side == 'h' { x } else { y };
+
+        let (light, occlusion) = map.light_at(x, y, jitter, side);
+
+        WallStrip {
+            texture,
+            tex_u: (wall_x / tile_size).fract() * texture.width() as f32,
+            top: horizon - projected_height / 2,
+            bottom: horizon + projected_height / 2,
+            mult: shade(1. / perpendicular, light, 1.0),
+            occlusion,
+            fog_amount: map.fog.amount(distance),
+        }
+    }
+
+    fn base(&self) -> BaseShade {
+        BaseShade {
+            fraction: BASE_SHADE_FRACTION,
+            occlusion: self.occlusion,
+        }
+    }
+
+    fn draw(
+        &self,
+        x: i32,
+        pixels: &mut Band,
+        map: &Map,
+        settings: &RenderSettings,
+        colormap: &Colormap,
+    ) {
+        let (mult, fog_amount) = (self.mult, self.fog_amount);
+        match settings.pipeline {
+            // So dark (and clear) we don't need to copy anything
+            Pipeline::TrueColor if mult.iter().any(|m| *m > 0.00) || fog_amount > 0.0 => {
+                if settings.filter == Filter::Bilinear {
+                    self.texture.draw_strip_filtered(
+                        x,
+                        (self.tex_u * 65536.0) as i32,
+                        self.top,
+                        self.bottom,
+                        pixels,
+                        Some(&mult),
+                        Some((&map.fog, fog_amount)),
+                        Some(self.base()),
+                    );
+                } else {
+                    self.texture.draw_strip_at_ex(
+                        x,
+                        self.tex_u as i32,
+                        self.top,
+                        self.bottom,
+                        pixels,
+                        Some(&mult),
+                        Some((&map.fog, fog_amount)),
+                        Some(self.base()),
+                    );
+                }
+            }
+            Pipeline::TrueColor => {}
+            // A strip only gets the one light level here, so the whole of it is occluded
+            Pipeline::Indexed => {
+                let occluded = [
+                    mult[0] * self.occlusion,
+                    mult[1] * self.occlusion,
+                    mult[2] * self.occlusion,
+                ];
+                self.texture.draw_strip_indexed(
                     x,
-                    tex_x,
-                    wall_top,
-                    wall_bottom,
+                    self.tex_u as i32,
+                    self.top,
+                    self.bottom,
                     pixels,
-                    Some(&[mult, mult, mult]),
+                    colormap,
+                    colormap.light_level(occluded),
+                    colormap.fog_level(fog_amount),
                 );
             }
+        }
+    }
 
-            let angle = rotation.rotated(-ray_rotation.degrees());
+    // Mixes the strip with whatever is already drawn there
+    fn draw_over(&self, x: i32, pixels: &mut Band, map: &Map, opacity: f32) {
+        self.texture.blend_strip(
+            x,
+            self.tex_u as i32,
+            self.top,
+            self.bottom,
+            pixels,
+            Some(&self.mult),
+            Some((&map.fog, self.fog_amount)),
+            Some(self.base()),
+            opacity,
+        );
+    }
 
-            floorcast(
-                x,
-                wall_bottom..projection_plane.1,
-                &position,
-                &ray_rotation,
-                angle.clone(),
-                distance_to_plane,
-                projection_plane,
-                pixels,
-                floor_texture,
-                'f',
-                &map,
-                &mut rng,
-            )?;
-
-            floorcast(
-                x,
-                0..wall_top,
-                &position,
-                &ray_rotation,
-                angle,
-                distance_to_plane,
-                projection_plane,
-                pixels,
-                floor_texture,
-                'c',
-                &map,
-                &mut rng,
-            )?;
-        }
+    // The texel that shows `height` world units up the wall, for reflections in the floor
+    fn texel_at_height(&self, height: f32) -> (i32, i32) {
+        let v = 1.0 - height / TILE_SIZE as f32;
+        (self.tex_u as i32, (v * self.texture.height() as f32) as i32)
+    }
 
-        // Done, next angle
-        ray_rotation.add(degrees_per_iteration);
+    // The light `height` world units up the wall, darker near its base
+    fn mult_at_height(&self, height: f32) -> [f32; 3] {
+        let shade = self.base().at(height / TILE_SIZE as f32);
+        let [r, g, b] = self.mult;
+        [r * shade, g * shade, b * shade]
     }
+}
 
-    Ok(())
+// Where a column's ray ran into a wall
+struct Hit {
+    x: f32,
+    y: f32,
+    tile: (i32, i32),
+    side: char,
+    // How far along the ray the wall is
+    distance: f32,
+    // How far straight ahead of the camera the wall is, which is what its height depends on
+    perpendicular: f32,
+    // How far the ray moves in x and y for every unit it gets away from the camera
+    step: (f32, f32),
+    // How much longer the ray is than the straight distance
+    secant: f32,
+}
+
+fn cast_float(
+    column: &Column,
+    rotation: &Rotation,
+    position: &Position,
+    map: &Map,
+) -> Result<Option<Hit>, String> {
+    let ray_rotation = &rotation.rotated(column.degrees);
+    let (intersection, closest_hit, side) = match nearest_wall(ray_rotation, position, map)? {
+        Some(nearest) => nearest,
+        None => return Ok(None),
+    };
+
+    let (cos, sin) = (rotation.cos(), rotation.sin());
+    Ok(Some(Hit {
+        x: intersection.x,
+        y: intersection.y,
+        tile: intersection.as_grid_pair(),
+        side,
+        distance: closest_hit,
+        perpendicular: closest_hit * column.cos,
+        step: (cos - sin * column.tan, sin + cos * column.tan),
+        secant: column.secant,
+    }))
+}
+
+// The first wall along a ray, how far away it is and which side of it got hit
+fn nearest_wall(
+    ray_rotation: &Rotation,
+    position: &Position,
+    map: &Map,
+) -> Result<Option<(IntersectionPoint, f32, char)>, String> {
+    let horizontal_distance = if ray_rotation.is_straight_horizontal() {
+        (IntersectionPoint::default(), f32::MAX)
+    } else {
+        look_for_horizontal(ray_rotation, position, map)?
+    };
+    let vertical_distance = if ray_rotation.is_straight_vertical() {
+        (IntersectionPoint::default(), f32::MAX)
+    } else {
+        look_for_vertical(ray_rotation, position, map)?
+    };
+
+    // Drawing some debug lines for the rays
+    /*
+    canvas.set_draw_color((20, 50, 20));
+    let ray_dir = ray_rotation.direction() * 5.0;
+    let some_distance_away = (position.x + ray_dir.x, position.y + ray_dir.y);
+
+    canvas.draw_line(
+        (position.x.floor() as i32, position.y.floor() as i32),
+        (
+            some_distance_away.0.floor() as i32,
+            some_distance_away.1.floor() as i32,
+        ),
+    )?;
+    */
+
+    let ((intersection, closest_hit), side) = if horizontal_distance.1 < vertical_distance.1 {
+        (horizontal_distance, 'h')
+    } else {
+        (vertical_distance, 'v')
+    };
+
+    if closest_hit == f32::MAX {
+        return Ok(None);
+    }
+
+    Ok(Some((intersection, closest_hit, side)))
+}
+
+// Same as cast_float, but stepping through the grid in fixed point with the trig
+// tables, so the same view always hits the walls at exactly the same spots.
+// `view` is the angle the camera is looking at, and `position` is in fixed point too.
+fn cast_fixed(
+    column: &Column,
+    view: i32,
+    position: (i32, i32),
+    map: &Map,
+    trig: &Trig,
+) -> Option<Hit> {
+    let (point, tile, side, distance) =
+        nearest_wall_fixed(position, view + column.steps, map, trig)?;
+
+    let (view_cos, view_sin) = (trig.cos(view), trig.sin(view));
+    let perpendicular =
+        fixed::mul(point.0 - position.0, view_cos) + fixed::mul(point.1 - position.1, view_sin);
+
+    Some(Hit {
+        x: fixed::to_f32(point.0),
+        y: fixed::to_f32(point.1),
+        tile,
+        side,
+        distance: fixed::to_f32(distance),
+        perpendicular: fixed::to_f32(perpendicular),
+        step: (
+            fixed::to_f32(view_cos - fixed::mul(view_sin, column.tan_fixed)),
+            fixed::to_f32(view_sin + fixed::mul(view_cos, column.tan_fixed)),
+        ),
+        secant: fixed::to_f32(column.secant_fixed),
+    })
+}
+
+// nearest_wall in fixed point: where the first wall along the ray got hit, the tile
+// it's in, which side of it and how far along the ray it is
+fn nearest_wall_fixed(
+    position: (i32, i32),
+    angle: i32,
+    map: &Map,
+    trig: &Trig,
+) -> Option<((i32, i32), (i32, i32), char, i32)> {
+    let (cos, sin) = (trig.cos(angle), trig.sin(angle));
+    let along_ray =
+        |(x, y): (i32, i32)| fixed::mul(x - position.0, cos) + fixed::mul(y - position.1, sin);
+
+    let horizontal = step_fixed(position, angle, 'h', map, trig);
+    let vertical = step_fixed(position, angle, 'v', map, trig);
+    let ((point, tile), side) = match (horizontal, vertical) {
+        (Some(h), Some(v)) if along_ray(h.0) < along_ray(v.0) => (h, 'h'),
+        (_, Some(v)) => (v, 'v'),
+        (Some(h), None) => (h, 'h'),
+        (None, None) => return None,
+    };
+
+    Some((point, tile, side, along_ray(point)))
+}
+
+// Where a ray ends up after bouncing off the mirror it hit. It only bounces
+// the once, a mirror seen in a mirror just shows its own texture.
+struct Bounce {
+    x: f32,
+    y: f32,
+    tile: (i32, i32),
+    side: char,
+    // How far from the mirror
+    distance: f32,
+}
+
+// How far off the mirror a bounced ray starts, so it doesn't hit the mirror again
+const BOUNCE_OFFSET: f32 = 0.01;
+
+fn bounce_float(hit: &Hit, ray_rotation: &Rotation, map: &Map) -> Result<Option<Bounce>, String> {
+    // The part of the direction going into the wall flips, the part along it stays
+    let reflected = if hit.side == 'h' {
+        Rotation::new(-ray_rotation.degrees())
+    } else {
+        Rotation::new(180.0 - ray_rotation.degrees())
+    };
+    let start = &Position::new(hit.x, hit.y) + reflected.direction() * BOUNCE_OFFSET;
+
+    Ok(
+        nearest_wall(&reflected, &start, map)?.map(|(intersection, distance, side)| Bounce {
+            x: intersection.x,
+            y: intersection.y,
+            tile: intersection.as_grid_pair(),
+            side,
+            distance: distance + BOUNCE_OFFSET,
+        }),
+    )
+}
+
+// bounce_float, for rays cast in fixed point. Starting right on the grid line is
+// fine here, step_fixed looks at the tile on the far side of it.
+fn bounce_fixed(hit: &Hit, angle: i32, map: &Map, trig: &Trig) -> Option<Bounce> {
+    let reflected = if hit.side == 'h' {
+        -angle
+    } else {
+        fixed::ANGLES / 2 - angle
+    };
+    let start = (fixed::from_f32(hit.x), fixed::from_f32(hit.y));
+
+    let (point, tile, side, distance) = nearest_wall_fixed(start, reflected, map, trig)?;
+    Some(Bounce {
+        x: fixed::to_f32(point.0),
+        y: fixed::to_f32(point.1),
+        tile,
+        side,
+        distance: fixed::to_f32(distance),
+    })
+}
+
+// Follows either the horizontal ('h') or the vertical ('v') grid lines, like
+// look_for_horizontal and look_for_vertical do, until one of them is a wall.
+// Gives back where it hit and which tile the wall is.
+fn step_fixed(
+    (px, py): (i32, i32),
+    angle: i32,
+    side: char,
+    map: &Map,
+    trig: &Trig,
+) -> Option<((i32, i32), (i32, i32))> {
+    let tile = TILE_SIZE * fixed::ONE;
+
+    let (mut x, mut y, step, mod_x, mod_y) = if side == 'h' {
+        let sin = trig.sin(angle);
+        if sin == 0 {
+            return None;
+        }
+
+        let up = sin < 0;
+        let first_y = py.div_euclid(tile) * tile + if up { 0 } else { tile };
+        let step_y = if up { -tile } else { tile };
+        let cot = trig.cot(angle);
+        (
+            px + fixed::mul(first_y - py, cot),
+            first_y,
+            (fixed::mul(step_y, cot), step_y),
+            0,
+            if up { -1 } else { 0 },
+        )
+    } else {
+        let cos = trig.cos(angle);
+        if cos == 0 {
+            return None;
+        }
+
+        let left = cos < 0;
+        let first_x = px.div_euclid(tile) * tile + if left { 0 } else { tile };
+        let step_x = if left { -tile } else { tile };
+        let tan = trig.tan(angle);
+        (
+            first_x,
+            py + fixed::mul(first_x - px, tan),
+            (step_x, fixed::mul(step_x, tan)),
+            if left { -1 } else { 0 },
+            0,
+        )
+    };
+
+    loop {
+        let grid = (x.div_euclid(tile) + mod_x, y.div_euclid(tile) + mod_y);
+        if grid.0 < 0 || grid.1 < 0 || grid.0 >= map.width() || grid.1 >= map.height() {
+            return None;
+        }
+
+        if map.is_blocking_at(grid) {
+            return Some(((x, y), grid));
+        }
+
+        x = x.saturating_add(step.0);
+        y = y.saturating_add(step.1);
+    }
 }
 
 // Looks for horizontal grid lines
@@ -335,231 +829,1047 @@ impl Default for IntersectionPoint {
     }
 }
 
+// A camera placed by the map, whose picture is drawn into a wall texture every frame
+pub struct MapCamera {
+    pub texture: usize,
+    pub x: f32,
+    pub y: f32,
+    pub degrees: f32,
+}
+
 pub struct Map {
     tiles: Vec<char>,
+    // Which tiles are walls, and the ID of the texture in the wall tileset they use
+    walls: HashMap<char, usize>,
+    cameras: Vec<MapCamera>,
+    // Walls that reflect, and how much of what they reflect shows over their texture
+    mirrors: HashMap<char, f32>,
+    // The same for floor tiles, like wet stone or polished marble
+    shiny_floors: HashMap<char, f32>,
     width: i32,
     height: i32,
-    lights: Vec<(i32, i32)>,
-    light_data: Vec<Option<(i32, i32)>>,
+    lights: Vec<Light>,
+    lightmap: Lightmap,
+    lightmap_density: i32,
+    dynamic_lights: Vec<DynamicLight>,
+    fog: Fog,
 }
 
 impl Map {
-    pub fn new() -> Map {
+    // Loads a map from a text file of tiles, one row per line. The baked lights are
+    // cached next to it, and only baked again when the map or its lights change.
+    // '#' is a wall with the first texture, and lines like `A = 12` before the tiles
+    // add more kinds of walls using other textures of the tileset. `M = 12 @ 4 7 90`
+    // makes texture 12 live, showing what a camera in tile (4, 7) looking at 90 degrees sees.
+    // `A = 12 mirror 0.6` is a wall that reflects, and `~ = floor 0.3` a floor tile that
+    // does, the number being how strong the reflection is from 0 to 1.
+    // `fog = 96 112 128 24 120 exp` fills the map with fog of that color, starting 24
+    // units away and thickest at 120, thickening either `linear`ly or `exp`onentially.
+    // `lumels = 8` bakes the lights with 8 lumels along every tile edge instead of 4.
+    pub fn load(path: &str) -> Result<Map, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut map = Map::parse(&source).map_err(|e| format!("{}: {}", path, e))?;
+
+        let cache = Path::new(path).with_extension("lightmap");
+        let hash = map.light_hash();
+        match Lightmap::load(&cache, &map, map.lightmap_density, hash) {
+            Ok(lightmap) => map.lightmap = lightmap,
+            Err(_) => {
+                println!("Baking lights for {}", path);
+                map.lightmap = Lightmap::bake(&map, map.lightmap_density);
+                if let Err(e) = map.lightmap.save(&cache, hash) {
+                    println!("Couldn't cache lights for {}: {}", path, e);
+                }
+            }
+        }
+
+        Ok(map)
+    }
+
+    // Reads the tiles and places the lights, but doesn't bake them
+    fn parse(source: &str) -> Result<Map, String> {
+        let lines: Vec<&str> = source
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty())
+            .collect();
+
+        let mut walls = HashMap::new();
+        walls.insert('#', 0);
+        let mut cameras = Vec::new();
+        let mut mirrors = HashMap::new();
+        let mut shiny_floors = HashMap::new();
+        let mut fog = Fog::none();
+        let mut lightmap_density = DEFAULT_DENSITY;
+
+        let (legend, rows): (Vec<&str>, Vec<&str>) =
+            lines.into_iter().partition(|l| l.contains('='));
+        for line in legend {
+            let mut parts = line.splitn(2, '=').map(str::trim);
+            let (tile, id) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+            if tile == "fog" {
+                fog = parse_fog(id)?;
+                continue;
+            }
+
+            if tile == "lumels" {
+                lightmap_density = id
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|density| (1..=MAX_DENSITY).contains(density))
+                    .ok_or_else(|| {
+                        format!("'{}' is not between 1 and {} lumels", id, MAX_DENSITY)
+                    })?;
+                continue;
+            }
+
+            let mut chars = tile.chars();
+            let tile = match (chars.next(), chars.next()) {
+                (Some(c), None) if c != '.' && light_for(c, 0, 0).is_none() => c,
+                _ => return Err(format!("'{}' can't be a wall tile", tile)),
+            };
+
+            if id.starts_with("floor") {
+                shiny_floors.insert(tile, parse_strength(&id["floor".len()..])?);
+                continue;
+            }
+
+            let mut parts = id.splitn(2, '@').map(str::trim);
+            let (id, camera) = (parts.next().unwrap_or(""), parts.next());
+            let mut parts = id.splitn(2, "mirror").map(str::trim);
+            let (id, mirror) = (parts.next().unwrap_or(""), parts.next());
+            let id = id
+                .parse::<usize>()
+                .map_err(|_| format!("'{}' is not a texture ID", id))?;
+            walls.insert(tile, id);
+
+            if let Some(mirror) = mirror {
+                mirrors.insert(tile, parse_strength(mirror)?);
+            }
+
+            if let Some(camera) = camera {
+                cameras.push(parse_camera(camera, id)?);
+            }
+        }
+
+        let width = rows.first().map(|r| r.chars().count()).unwrap_or(0);
+        if width == 0 {
+            return Err("Map has no tiles".to_owned());
+        }
+
+        if let Some(i) = rows.iter().position(|r| r.chars().count() != width) {
+            return Err(format!("Row {} is not {} tiles wide", i + 1, width));
+        }
+
         let mut map = Map {
-            tiles: r#"
-                ##################
-                #.............####
-                #..............###
-                #.............####
-                #.............####
-                #.............####
-                #..............###
-                #..............###
-                #......#.......###
-                #.....#..#.....###
-                #........#.....###
-                #...##.#####...###
-                #...#..........###
-                #......l..#....###
-                #...##....#....###
-                #...####..#....###
-                #.........#....###
-                ##################
-            "#
-            .to_owned()
-            .replace('\n', "")
-            .replace(' ', "")
-            .chars()
-            .collect(),
-            width: 18,
-            height: 18,
+            tiles: rows.iter().flat_map(|r| r.chars()).collect(),
+            walls,
+            cameras,
+            mirrors,
+            shiny_floors,
+            width: width as i32,
+            height: rows.len() as i32,
             lights: Vec::new(),
-            light_data: Vec::new(),
+            lightmap: Lightmap::empty(),
+            lightmap_density,
+            dynamic_lights: Vec::new(),
+            fog,
         };
 
-        map.bake_lights();
-        map
+        map.collect_lights();
+        Ok(map)
     }
 
-    // Finds the closest light source for every tile on map
-    fn bake_lights(&mut self) {
+    // Places a light wherever there's a tile for one
+    fn collect_lights(&mut self) {
         self.lights.clear();
         for (i, t) in self.tiles.iter().enumerate() {
-            if *t == 'l' {
-                let x = (i as i32 % self.width) * TILE_SIZE;
-                let y = (i as i32 / self.width) * TILE_SIZE;
-                self.lights
-                    .push((x, y));
+            let x = (i as i32 % self.width) * TILE_SIZE;
+            let y = (i as i32 / self.width) * TILE_SIZE;
+            if let Some(light) = light_for(*t, x, y) {
+                self.lights.push(light);
             }
         }
+    }
+
+    // Adds up the light reaching each point on the map from every light source
+    fn bake_lights(&mut self) {
+        self.collect_lights();
+        self.lightmap = Lightmap::bake(self, self.lightmap_density);
+    }
 
-        let total_width = self.width * TILE_SIZE;
-        let mut light_data = vec![None; (self.width * self.height * TILE_SIZE * TILE_SIZE) as usize];
+    // Fingerprint of everything that goes into baking the lights,
+    // used to tell when a cached bake is out of date
+    fn light_hash(&self) -> u64 {
+        let tiles: String = self.tiles.iter().collect();
+        // Only which tiles are walls changes the light, not their textures
+        let mut walls: Vec<char> = self.walls.keys().copied().collect();
+        walls.sort_unstable();
+        let walls: String = walls.into_iter().collect();
+
+        let mut hash = util::fnv1a(util::FNV_OFFSET, &BAKE_VERSION.to_le_bytes());
+        hash = util::fnv1a(hash, &self.width.to_le_bytes());
+        hash = util::fnv1a(hash, &self.lightmap_density.to_le_bytes());
+        hash = util::fnv1a(hash, tiles.as_bytes());
+        hash = util::fnv1a(hash, walls.as_bytes());
+        util::fnv1a(hash, format!("{:?}", self.lights).as_bytes())
+    }
 
-        for x in 0..total_width {
-            for y in 0..self.height * TILE_SIZE {
-                let light_pos = self.prepare_light_data(x, y);
-                light_data[(total_width * y + x) as usize] = light_pos;
-            }
+    // Changes a tile, re-baking only the light that could've been seen through it
+    pub fn set_tile(&mut self, (x, y): (i32, i32), tile: char) {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return;
         }
 
-        self.light_data = light_data;
+        let idx = (self.width * y + x) as usize;
+        let old = self.tiles[idx];
+        if old == tile {
+            return;
+        }
+
+        // Lights come from the tiles themselves, and adding or removing
+        // one can change every layer, so that needs a full bake
+        if light_for(old, 0, 0).is_some() || light_for(tile, 0, 0).is_some() {
+            self.tiles[idx] = tile;
+            self.bake_lights();
+            return;
+        }
+
+        // Nothing can see any differently through it
+        if self.is_wall(old) == self.is_wall(tile) {
+            self.tiles[idx] = tile;
+            return;
+        }
+
+        let crossings = self.lightmap.crossings(self, (x, y));
+        self.tiles[idx] = tile;
+
+        let mut lightmap = std::mem::replace(&mut self.lightmap, Lightmap::empty());
+        lightmap.retile(self, (x, y), crossings);
+        self.lightmap = lightmap;
+    }
+
+    // Updates how bright every animated light is, without baking again
+    pub fn animate_lights(&mut self, time: f64) {
+        self.lightmap.animate(time);
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    pub fn cameras(&self) -> &[MapCamera] {
+        &self.cameras
+    }
+
+    pub fn fog(&self) -> &Fog {
+        &self.fog
     }
 
-    fn is_blocking_at(&self, (x, y): (i32, i32)) -> bool {
+    pub fn is_blocking_at(&self, (x, y): (i32, i32)) -> bool {
         let given_idx = (self.width * y + x) as usize;
         if y > self.height || x > self.width || given_idx >= self.tiles.len() {
             return false;
         }
-        self.tiles[given_idx] == '#'
+        self.is_wall(self.tiles[given_idx])
     }
 
-    fn prepare_light_data(&self, x: i32, y: i32) -> Option<(i32, i32)> {
-        let mut closest = None;
-        for (lx, ly) in &self.lights {
-            let dst = if let Some(_) =
-                crate::util::raycast((x as i32, y as i32), (*lx as i32, *ly as i32), |point| {
-                    let x_diff = (point.0 - x).abs();
-                    let y_diff = (point.1 - y).abs();
-                    if x_diff < 2 && y_diff < 2 { return false; }
-                    self.is_blocking_at((point.0 / TILE_SIZE, point.1 / TILE_SIZE))
-                }) {
-                f32::MAX
-            } else {
-                let x = ((x - lx) as f32).abs();
-                let y = ((y - ly) as f32).abs();
-                x.hypot(y)
-            };
+    fn is_wall(&self, tile: char) -> bool {
+        self.walls.contains_key(&tile)
+    }
 
-            if let Some((c, _)) = closest {
-                if dst < c {
-                    closest = Some((dst, (*lx, *ly)));
-                }
-            } else {
-                closest = Some((dst, (*lx, *ly)));
-            }
+    // How strongly the wall at the given tile reflects, if it's a mirror
+    pub fn mirror_at(&self, (x, y): (i32, i32)) -> Option<f32> {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return None;
         }
+        let tile = self.tiles[(self.width * y + x) as usize];
+        self.mirrors.get(&tile).copied()
+    }
 
-        if let Some(closest) = closest {
-            if closest.0 == f32::MAX {
-                return None;
-            }
-            Some(closest.1)
-        } else {
-            None
+    // How strongly the floor reflects at a point on the map, if it does at all
+    pub fn shine_at(&self, x: f32, y: f32) -> Option<f32> {
+        let (x, y) = (
+            (x / TILE_SIZE as f32).floor() as i32,
+            (y / TILE_SIZE as f32).floor() as i32,
+        );
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return None;
         }
+        let tile = self.tiles[(self.width * y + x) as usize];
+        self.shiny_floors.get(&tile).copied()
     }
 
-    pub fn distance_to_light(&self, x: f32, y: f32, rng: Option<&mut rand::rngs::SmallRng>, side: char) -> Option<f32> {
-        let gx = x.round() as i32;
-        let gy = y.round() as i32;
-        let idx = (self.width * TILE_SIZE * gy + gx) as usize;
+    // ID of the texture the wall at the given tile uses
+    pub fn wall_texture_at(&self, (x, y): (i32, i32)) -> usize {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return 0;
+        }
+        let tile = self.tiles[(self.width * y + x) as usize];
+        self.walls.get(&tile).copied().unwrap_or(0)
+    }
 
-        if idx >= self.light_data.len() {
-            return None;
+    // Makes sure every kind of wall has a texture in a tileset with `count` of them,
+    // since the tileset would quietly draw the first one instead
+    pub fn check_wall_textures(&self, count: usize) -> Result<(), String> {
+        let mut walls: Vec<(&char, &usize)> = self.walls.iter().collect();
+        walls.sort_unstable();
+        for (tile, id) in walls {
+            if *id >= count {
+                return Err(format!(
+                    "'{}' uses wall texture {} but there are only {}",
+                    tile, id, count
+                ));
+            }
         }
+        Ok(())
+    }
+
+    // Replaces last frame's moving lights, working out what each of them can see
+    pub fn set_dynamic_lights(&mut self, lights: &[Light]) {
+        let dynamic_lights = lights
+            .iter()
+            .map(|light| {
+                DynamicLight::new(*light, |(x, y)| {
+                    self.is_lit_by((x as f32, y as f32), (light.x as f32, light.y as f32))
+                })
+            })
+            .collect();
+
+        self.dynamic_lights = dynamic_lights;
+    }
 
+    // Whether light can travel from `light` to the tile the given point is in,
+    // walking the grid one tile at a time and stopping at the first wall.
+    pub fn is_lit_by(&self, (x, y): (f32, f32), (lx, ly): (f32, f32)) -> bool {
         let tile_size = TILE_SIZE as f32;
-        if let Some((lx, ly)) = self.light_data[idx] {
+        let target = (
+            (x / tile_size).floor() as i32,
+            (y / tile_size).floor() as i32,
+        );
+        let (mut tx, mut ty) = (
+            (lx / tile_size).floor() as i32,
+            (ly / tile_size).floor() as i32,
+        );
 
-            let dither = if let Some(rng) = rng {
-                if side == 'c' || side == 'f' {
-                    rng.gen_range(1.,18.)
-                } else {
-                    rng.gen_range(1.,2.)
-                }
+        let (dx, dy) = (x - lx, y - ly);
+        let (step_x, step_y) = (dx.signum() as i32, dy.signum() as i32);
+
+        // How far along the line (from 0 to 1) it takes to cross a whole tile,
+        // and how far until the next vertical and horizontal grid lines
+        let (delta_x, mut next_x) = if dx != 0.0 {
+            let next = if dx > 0.0 {
+                (tx + 1) as f32 * tile_size - lx
             } else {
-                0.0
+                lx - tx as f32 * tile_size
             };
-            
-            let sign = if side == 'h' {
-                (gx - ly).signum()
+            (tile_size / dx.abs(), next / dx.abs())
+        } else {
+            (f32::MAX, f32::MAX)
+        };
+        let (delta_y, mut next_y) = if dy != 0.0 {
+            let next = if dy > 0.0 {
+                (ty + 1) as f32 * tile_size - ly
             } else {
-                (gy - lx).signum()
-            } as f32;
+                ly - ty as f32 * tile_size
+            };
+            (tile_size / dy.abs(), next / dy.abs())
+        } else {
+            (f32::MAX, f32::MAX)
+        };
 
-            let (lx, ly) = (
-                lx as f32 + if side == 'h' { dither * sign } else { 0. },
-                ly as f32 + if side == 'v' { dither * sign } else { 0. },
-            );
+        loop {
+            if (tx, ty) == target {
+                return true;
+            }
 
-            let dst = (lx - x).abs().hypot((ly - y).abs());
-            return Some(dst + if side == 'c' || side == 'f' { dither } else { 0.0 });
+            if self.is_blocking_at((tx, ty)) {
+                return false;
+            }
+
+            if next_x > 1.0 && next_y > 1.0 {
+                return true;
+            }
+
+            if next_x < next_y {
+                next_x += delta_x;
+                tx += step_x;
+            } else {
+                next_y += delta_y;
+                ty += step_y;
+            }
+        }
+    }
+
+    // Looks up the baked light at the given point, plus whatever the dynamic lights add,
+    // and how much the surroundings occlude it. The lookup is jittered by up to half
+    // a tile (scaled by `jitter`, from -1 to 1) so the edges of the light don't band,
+    // but never out of the tile or wall face the point is on.
+    pub fn light_at(&self, x: f32, y: f32, jitter: (f32, f32), side: char) -> ([f32; 3], f32) {
+        let tile_size = TILE_SIZE as f32;
+        let ((mut total, occlusion), (gx, gy)) = match side {
+            'c' | 'f' => {
+                // Kept just short of the next tile over, so it still floors to this one
+                let within = |v: f32, jitter: f32| {
+                    let start = (v / tile_size).floor() * tile_size;
+                    (v + jitter * tile_size / 2.)
+                        .max(start)
+                        .min(start + tile_size - 0.001)
+                };
+                let (x, y) = (within(x, jitter.0), within(y, jitter.1));
+                (self.lightmap.sample_floor(x, y), (x, y))
+            }
+            _ => {
+                let (tile, face, along) = self.wall_face_at(x, y, side);
+                // Only jitter along the wall, otherwise we could end up inside it
+                let along = (along + jitter.0 * 2. / tile_size).max(0.).min(1.);
+                // Dynamic lights are checked from just off the face, in the floor it looks
+                // into like the lightmap does, or a light behind a thin wall shines through it
+                let edge = |t: i32| t as f32 * tile_size;
+                let on_face = |t: i32| (edge(t) + along * tile_size).min(edge(t + 1) - 0.001);
+                let point = match face {
+                    Face::North => (on_face(tile.0), edge(tile.1) - 1.0),
+                    Face::South => (on_face(tile.0), edge(tile.1 + 1) + 1.0),
+                    Face::West => (edge(tile.0) - 1.0, on_face(tile.1)),
+                    Face::East => (edge(tile.0 + 1) + 1.0, on_face(tile.1)),
+                };
+                (self.lightmap.sample_wall(tile, face, along), point)
+            }
+        };
+
+        let (gx, gy) = (gx.floor() as i32, gy.floor() as i32);
+        for dynamic in &self.dynamic_lights {
+            if let Some([r, g, b]) = dynamic.contribution_at(gx, gy) {
+                total[0] += r;
+                total[1] += g;
+                total[2] += b;
+            }
+        }
+
+        (total, occlusion)
+    }
+
+    // Works out which wall face a ray hit from where it crossed the grid line,
+    // along with how far along that face it landed
+    fn wall_face_at(&self, x: f32, y: f32, side: char) -> ((i32, i32), Face, f32) {
+        let tile_size = TILE_SIZE as f32;
+        if side == 'h' {
+            let tx = (x / tile_size).floor() as i32;
+            let below = (y / tile_size).round() as i32;
+            let along = x / tile_size - tx as f32;
+            if self.is_blocking_at((tx, below)) {
+                ((tx, below), Face::North, along)
+            } else {
+                ((tx, below - 1), Face::South, along)
+            }
+        } else {
+            let ty = (y / tile_size).floor() as i32;
+            let right = (x / tile_size).round() as i32;
+            let along = y / tile_size - ty as f32;
+            if self.is_blocking_at((right, ty)) {
+                ((right, ty), Face::West, along)
+            } else {
+                ((right - 1, ty), Face::East, along)
+            }
         }
-        None
+    }
+}
+
+// How much a mirror or shiny floor reflects, from 0 to 1
+fn parse_strength(source: &str) -> Result<f32, String> {
+    source
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|strength| (0.0..=1.0).contains(strength))
+        .ok_or_else(|| {
+            format!(
+                "'{}' is not a reflection strength between 0 and 1",
+                source.trim()
+            )
+        })
+}
+
+// The `96 112 128 24 120 exp` of a fog line: its color, where it starts and where
+// it's at its thickest, and whether it thickens linearly or exponentially
+fn parse_fog(source: &str) -> Result<Fog, String> {
+    let error = || {
+        format!(
+            "'{}' is not a fog color, start, end and linear or exp",
+            source
+        )
+    };
+    let parts: Vec<&str> = source.split_whitespace().collect();
+    let (r, g, b, start, end, mode) = match parts.as_slice() {
+        [r, g, b, start, end, mode] => (*r, *g, *b, *start, *end, *mode),
+        _ => return Err(error()),
+    };
+
+    let channel = |c: &str| c.parse::<u8>().map_err(|_| error());
+    let color = [channel(r)?, channel(g)?, channel(b)?];
+    let (start, end) = match (start.parse::<f32>(), end.parse::<f32>()) {
+        (Ok(start), Ok(end)) if start >= 0.0 && end > start => (start, end),
+        _ => return Err(error()),
+    };
+    let mode = match mode {
+        "linear" => FogMode::Linear,
+        "exp" => FogMode::Exponential,
+        _ => return Err(error()),
+    };
+
+    Ok(Fog::new(color, start, end, mode))
+}
+
+// The `4 7 90` part of a live texture, the camera's tile and which way it looks
+fn parse_camera(source: &str, texture: usize) -> Result<MapCamera, String> {
+    let numbers = source
+        .split_whitespace()
+        .map(|n| n.parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()
+        .map_err(|_| format!("'{}' is not a tile and an angle", source))?;
+
+    match numbers.as_slice() {
+        [x, y, degrees] => Ok(MapCamera {
+            texture,
+            x: (x + 0.5) * TILE_SIZE as f32,
+            y: (y + 0.5) * TILE_SIZE as f32,
+            degrees: *degrees,
+        }),
+        _ => Err(format!("'{}' is not a tile and an angle", source)),
+    }
+}
+
+// The light a tile character places on the map, if any
+fn light_for(tile: char, x: i32, y: i32) -> Option<Light> {
+    match tile {
+        'l' => Some(Light::torch(x, y)),
+        't' => Some(Light::terminal(x, y)),
+        'p' => Some(Light::lamp(x, y)),
+        'a' => Some(Light::alarm(x, y)),
+        _ => None,
     }
 }
 
 const PLAYER_HEIGHT: i32 = TILE_SIZE / 2;
-fn floorcast(
+
+// A mirror a column's ray bounced off
+struct Mirror {
+    side: char,
+    // The grid line it's on, y for 'h' sides and x for 'v' ones
+    line: f32,
+    // How far straight ahead of the camera it is
+    distance: f32,
+}
+
+impl Mirror {
+    // Where a point seen past the mirror really is
+    fn reflect(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        if self.side == 'h' {
+            (x, 2.0 * self.line - y)
+        } else {
+            (2.0 * self.line - x, y)
+        }
+    }
+}
+
+// What the floor caster needs to know about a column whose ray hit a wall
+struct FloorColumn<'a> {
     x: i32,
-    range: std::ops::Range<i32>,
+    // The wall the column ends at, which shiny floors reflect too
+    wall: WallStrip<'a>,
+    // How far straight ahead of the camera the wall is
+    wall_distance: f32,
+    // How far the ray moves in x and y for every unit it gets away from the camera,
+    // so a row's world position is just the row's distance times this
+    step: (f32, f32),
+    // How much longer the ray is than the straight distance
+    secant: f32,
+    mirror: Option<Mirror>,
+}
+
+impl<'a> FloorColumn<'a> {
+    fn new(
+        x: i32,
+        wall: WallStrip<'a>,
+        wall_distance: f32,
+        hit: &Hit,
+        mirror: Option<Mirror>,
+    ) -> FloorColumn<'a> {
+        FloorColumn {
+            x,
+            wall,
+            wall_distance,
+            step: hit.step,
+            secant: hit.secant,
+            mirror,
+        }
+    }
+
+    // Where on the map a row `distance` straight ahead of the camera is
+    fn point_at(&self, player: &Position, distance: f32) -> (f32, f32) {
+        let point = (
+            distance * self.step.0 + player.x,
+            distance * self.step.1 + player.y,
+        );
+        match &self.mirror {
+            Some(mirror) if distance > mirror.distance => mirror.reflect(point),
+            _ => point,
+        }
+    }
+}
+
+// Draws floors and ceilings a row at a time, like a scanline renderer. Everything in
+// a row is the same distance straight ahead, so the trig is done once per column
+// and once per row instead of for every pixel.
+fn floorcast(
+    columns: &[FloorColumn],
     player: &Position,
-    ray: &Rotation,
-    angle: Rotation,
     distance_to_plane: f32,
     projection_plane: (i32, i32),
-    pixels: &mut [u8],
+    horizon: i32,
+    pixels: &mut Band,
     floor_texture: &Texture,
-    side: char,
     map: &Map,
-    rng: &mut rand::rngs::SmallRng,
+    dither: &Dither,
+    settings: &RenderSettings,
+    colormap: &Colormap,
 ) -> Result<(), String> {
-    let projection_center = projection_plane.1 / 2;
     let tile_size = TILE_SIZE as f32;
+    let reflections = settings.reflections && settings.pipeline == Pipeline::TrueColor;
 
-    for row in range {
-        let bheight = if side == 'f' {
-            row - projection_center
+    for row in 0..projection_plane.1 {
+        let (side, bheight) = if row > horizon {
+            ('f', row - horizon)
         } else {
-            projection_center - row
+            ('c', horizon - row)
         };
+        if bheight <= 0 {
+            continue;
+        }
+
         let straight_distance =
             (PLAYER_HEIGHT as f32 / (bheight) as f32) * distance_to_plane as f32;
 
-        let distance_to_point = straight_distance / angle.cos();
+        // A pixel covers more floor going away from the camera than across, the
+        // geometric mean of both keeps it from shimmering without smearing it too much
+        let floor_texture = if settings.mipmaps {
+            let across = straight_distance / distance_to_plane;
+            let away = straight_distance / bheight as f32;
+            let footprint = (across * away).sqrt();
+            floor_texture.mip(footprint / tile_size * floor_texture.width() as f32)
+        } else {
+            floor_texture
+        };
 
-        let ends = (
-            distance_to_point * ray.cos() + player.x,
-            distance_to_point * ray.sin() + player.y,
-        );
+        for column in columns {
+            // Behind the wall
+            if (side == 'f' && row < column.wall.bottom) || (side == 'c' && row >= column.wall.top)
+            {
+                continue;
+            }
 
-        let tex_x = ((ends.0 / tile_size).fract() * floor_texture.width() as f32) as i32;
-        let tex_y = ((ends.1 / tile_size).fract() * floor_texture.height() as f32) as i32;
+            let x = column.x;
+            let distance_to_point = straight_distance * column.secant;
 
-        let distance_to_light = map.distance_to_light(ends.0, ends.1, Some(rng), side);
-        let light_mult = light_intensity(distance_to_light);
-        if light_mult < 0.08 {
-            // light_mult = 0.;
-        }
+            let ends = column.point_at(player, straight_distance);
 
-        let mult = 1. / distance_to_point + light_mult;
+            let tex_u = (ends.0 / tile_size).fract() * floor_texture.width() as f32;
+            let tex_v = (ends.1 / tile_size).fract() * floor_texture.height() as f32;
+            let (tex_x, tex_y) = (tex_u as i32, tex_v as i32);
 
-        // So dark we don't need to copy anything
-        if mult < 0.005 {
-            // continue;
-        }
+            let (light, occlusion) = map.light_at(ends.0, ends.1, dither.at(x, row), side);
 
-        floor_texture.copy_to_ex(tex_x, tex_y, x, row, pixels, Some(&[mult, mult, mult]));
+            let mult = shade(1. / distance_to_point, light, occlusion);
+
+            let fog_amount = map.fog.amount(distance_to_point);
+
+            match settings.pipeline {
+                Pipeline::TrueColor if settings.filter == Filter::Bilinear => floor_texture
+                    .copy_filtered(
+                        (tex_u * 65536.0) as i32,
+                        (tex_v * 65536.0) as i32,
+                        x,
+                        row,
+                        pixels,
+                        Some(&mult),
+                        Some((&map.fog, fog_amount)),
+                    ),
+                Pipeline::TrueColor => floor_texture.copy_to_ex(
+                    tex_x,
+                    tex_y,
+                    x,
+                    row,
+                    pixels,
+                    Some(&mult),
+                    Some((&map.fog, fog_amount)),
+                ),
+                Pipeline::Indexed => floor_texture.copy_indexed(
+                    tex_x,
+                    tex_y,
+                    x,
+                    row,
+                    pixels,
+                    colormap,
+                    colormap.light_level(mult),
+                    colormap.fog_level(fog_amount),
+                ),
+            }
+
+            if side == 'f' && reflections {
+                if let Some(strength) = map.shine_at(ends.0, ends.1) {
+                    reflect_floor(
+                        column,
+                        straight_distance,
+                        (x, row),
+                        strength,
+                        player,
+                        pixels,
+                        floor_texture,
+                        map,
+                        dither.at(x, row),
+                    );
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
-fn light_intensity(dtl: Option<f32>) -> f32 {
-    let intensity = if let Some(dtl) = dtl {
-        let rounded = util::round_n(dtl, (TILE_SIZE / 2) as f32);
-        (1.0 / rounded.powf(if dtl < 60. {
-            0.95
-        } else if dtl > 100. {
-            2.
-        } else {
-            1.15
-        }))
-        .sqrt()
+// Mixes what a shiny floor reflects into the floor pixel already drawn at `(x, row)`.
+// The floor is a flat mirror halfway between the camera and the ceiling, so the
+// reflected ray climbs as fast as the real one dropped: by the time it's 3 times as far
+// out it's at the ceiling, and if the wall is closer than that it hits the wall first.
+fn reflect_floor(
+    column: &FloorColumn,
+    distance: f32,
+    (x, row): (i32, i32),
+    strength: f32,
+    player: &Position,
+    pixels: &mut Band,
+    ceiling_texture: &Texture,
+    map: &Map,
+    jitter: (f32, f32),
+) {
+    let tile_size = TILE_SIZE as f32;
+
+    if distance * 3.0 >= column.wall_distance {
+        let height = (column.wall_distance - distance) / distance * PLAYER_HEIGHT as f32;
+        let wall = &column.wall;
+        let (tex_x, tex_y) = wall.texel_at_height(height);
+        wall.texture.copy_blended(
+            tex_x,
+            tex_y,
+            x,
+            row,
+            pixels,
+            Some(&wall.mult_at_height(height)),
+            Some((&map.fog, wall.fog_amount)),
+            strength,
+        );
     } else {
-        0.0
-    };
+        let ceiling_distance = distance * 3.0;
+        let point = column.point_at(player, ceiling_distance);
+        let distance_to_point = ceiling_distance * column.secant;
+
+        let tex_u = (point.0 / tile_size).fract() * ceiling_texture.width() as f32;
+        let tex_v = (point.1 / tile_size).fract() * ceiling_texture.height() as f32;
+        let (light, occlusion) = map.light_at(point.0, point.1, jitter, 'c');
+
+        ceiling_texture.copy_blended(
+            tex_u as i32,
+            tex_v as i32,
+            x,
+            row,
+            pixels,
+            Some(&shade(1. / distance_to_point, light, occlusion)),
+            Some((&map.fog, map.fog.amount(distance_to_point))),
+            strength,
+        );
+    }
+}
+
+// Distance shading is the same for every channel, the lights are what give it color,
+// and the whole thing gets darker in nooks and corners
+fn shade(distance_shade: f32, light: [f32; 3], occlusion: f32) -> [f32; 3] {
+    let [r, g, b] = light;
+    [
+        (distance_shade + r.min(MAX_LIGHT)) * occlusion,
+        (distance_shade + g.min(MAX_LIGHT)) * occlusion,
+        (distance_shade + b.min(MAX_LIGHT)) * occlusion,
+    ]
+}
+
+const MAX_LIGHT: f32 = 1.15;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Viewport;
+
+    const ROOM: &str = "
+        ############
+        #..........#
+        #...l......#
+        #..........#
+        #......t...#
+        #..........#
+        ############
+    ";
+
+    // Every lumel of both lightmaps, sampled through the same calls the renderer makes
+    fn assert_same_light(map: &Map, baked: &Lightmap) {
+        let close = |(a, ao): ([f32; 3], f32), (b, bo): ([f32; 3], f32)| {
+            a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 1e-4) && (ao - bo).abs() < 1e-4
+        };
+
+        let step = TILE_SIZE as f32 / 8.0;
+        for y in 0..map.height() * 8 {
+            for x in 0..map.width() * 8 {
+                let (x, y) = (x as f32 * step + 0.5, y as f32 * step + 0.5);
+                let (incremental, full) =
+                    (map.lightmap.sample_floor(x, y), baked.sample_floor(x, y));
+                assert!(
+                    close(incremental, full),
+                    "floor at ({}, {}): {:?} != {:?}",
+                    x,
+                    y,
+                    incremental,
+                    full
+                );
+            }
+        }
 
-    intensity.min(1.15)
+        let faces = [Face::North, Face::East, Face::South, Face::West];
+        for y in 0..map.height() {
+            for x in 0..map.width() {
+                for face in faces.iter() {
+                    for i in 0..=8 {
+                        let along = i as f32 / 8.0;
+                        let (incremental, full) = (
+                            map.lightmap.sample_wall((x, y), *face, along),
+                            baked.sample_wall((x, y), *face, along),
+                        );
+                        assert!(
+                            close(incremental, full),
+                            "{:?} face of ({}, {}): {:?} != {:?}",
+                            face,
+                            x,
+                            y,
+                            incremental,
+                            full
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn set_tile_matches_a_full_bake() {
+        let mut map = Map::parse(ROOM).unwrap();
+        map.bake_lights();
+
+        // Walls right next to a light, between the two lights, and one that
+        // only changes the occlusion of faces two tiles away
+        for &tile in &[(5, 2), (6, 3), (3, 4), (9, 1)] {
+            map.set_tile(tile, '#');
+            assert_same_light(&map, &Lightmap::bake(&map, map.lightmap_density));
+        }
+
+        for &tile in &[(6, 3), (5, 2), (9, 1), (3, 4)] {
+            map.set_tile(tile, '.');
+            assert_same_light(&map, &Lightmap::bake(&map, map.lightmap_density));
+        }
+    }
+
+    #[test]
+    fn dynamic_lights_stay_behind_walls() {
+        let mut map = Map::parse(
+            "
+            #######
+            #.....#
+            #..#..#
+            #.....#
+            #######
+            ",
+        )
+        .unwrap();
+        // The west face of the wall in the middle, seen from the left
+        let face = |map: &Map| -> Vec<[f32; 3]> {
+            (0..24)
+                .map(|i| map.light_at(36.0, 24.0 + i as f32 * 0.5, (0.0, 0.0), 'v').0)
+                .collect()
+        };
+        let dark = face(&map);
+
+        // However the light moves around on the other side, none of it gets through
+        for step in 0..40 {
+            let x = 48 + step * 3 / 10;
+            map.set_dynamic_lights(&[Light::new(x, 30, [1.0; 3], 48.0, 1.0)]);
+            assert_eq!(face(&map), dark, "light at x = {} leaks through", x);
+        }
+
+        map.set_dynamic_lights(&[Light::new(20, 30, [1.0; 3], 48.0, 1.0)]);
+        assert!(face(&map)[12][0] > dark[12][0]);
+    }
+
+    #[test]
+    fn jitter_stays_on_what_was_hit() {
+        let mut map = Map::parse(
+            "
+            #######
+            #.....#
+            #.l#..#
+            #.....#
+            #######
+            ",
+        )
+        .unwrap();
+        map.bake_lights();
+
+        // Up and down the lit west face of the pillar, right to its ends, and
+        // the floor a unit in front of it
+        for i in 0..24 {
+            let y = 24.0 + i as f32 * 0.5;
+            for &jitter in &[(-1.0, -1.0), (-1.0, 1.0), (1.0, -1.0), (1.0, 1.0)] {
+                for &(x, side) in &[(36.0, 'v'), (35.0, 'f')] {
+                    let (light, _) = map.light_at(x, y, jitter, side);
+                    assert!(
+                        light.iter().any(|c| *c > 0.0),
+                        "no light at ({}, {}) on '{}' with {:?}",
+                        x,
+                        y,
+                        side,
+                        jitter
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn maps_set_their_own_fog() {
+        let map = Map::parse("fog = 96 112 128 24 120 exp\n###\n#.#\n###").unwrap();
+        let fog = map.fog();
+        assert_eq!(fog.color, [96, 112, 128]);
+        assert_eq!(
+            (fog.start, fog.end, fog.mode),
+            (24.0, 120.0, FogMode::Exponential)
+        );
+
+        assert!(Map::parse("fog = 96 112 128 24 120\n###\n#.#\n###").is_err());
+        assert!(Map::parse("fog = 96 112 300 24 120 linear\n###\n#.#\n###").is_err());
+    }
+
+    #[test]
+    fn wall_textures_have_to_exist() {
+        let map = Map::parse("A = 1\nB = 3\n#A#\n#.#\n#B#").unwrap();
+        assert!(map.check_wall_textures(4).is_ok());
+        assert!(map.check_wall_textures(3).is_err());
+    }
+
+    // Hash of what the default map looks like from a few spots, drawn with fixed point
+    // math split into however many bands the settings ask for
+    fn fixed_frames(
+        map: &mut Map,
+        (walls, floor_texture, colormap): &(Tileset, Texture, Colormap),
+        settings: &RenderSettings,
+        trig: &Trig,
+    ) -> u64 {
+        let mut camera = Camera::new(66.0, Viewport::new(0, 0, 160, 100));
+        let rays = RayTable::new(160, 66.0, settings.spacing, settings.math, trig);
+        let mut hash = util::FNV_OFFSET;
+        for &(x, y, degrees, pitch) in &[
+            (120.0, 60.0, 0.0, 0.0),
+            (30.0, 66.0, 10.0, -12.0),
+            (66.0, 150.0, 300.0, 20.0),
+        ] {
+            camera.pitch = pitch;
+            map.set_dynamic_lights(&[Light::new(x as i32 + 8, y as i32, [1.0; 3], 48.0, 0.5)]);
+
+            let mut pixels = vec![0; 160 * 100 * 4];
+            raycast(
+                (160, 100),
+                &camera,
+                &rays,
+                &Position::new(x, y),
+                &Rotation::new(degrees),
+                &mut pixels,
+                walls,
+                floor_texture,
+                map,
+                settings,
+                colormap,
+                trig,
+            )
+            .unwrap();
+            hash = util::fnv1a(hash, &pixels);
+        }
+
+        hash
+    }
+
+    // Whatever changes these has to have meant to change how the default map looks
+    // in fixed point, and they're updated along with it. The same numbers on every
+    // platform is the whole point of Math::Fixed.
+    #[test]
+    fn fixed_frames_match_the_golden_hashes() {
+        let mut map = Map::parse(include_str!("../assets/maps/default.map")).unwrap();
+        map.bake_lights();
+        map.animate_lights(1.25);
+        let textures = crate::load_textures(map.fog()).unwrap();
+        let trig = Trig::new();
+
+        for &(pipeline, golden) in &[
+            (Pipeline::TrueColor, 0x14a6_ceed_5cf0_7228),
+            (Pipeline::Indexed, 0x9106_f60c_cf7b_621b),
+        ] {
+            let settings = RenderSettings {
+                math: Math::Fixed,
+                pipeline,
+                threads: 1,
+                ..RenderSettings::default()
+            };
+            let hash = fixed_frames(&mut map, &textures, &settings, &trig);
+            assert_eq!(hash, golden, "{:?} frames are {:#018x}", pipeline, hash);
+        }
+    }
+
+    #[test]
+    fn fixed_frames_dont_depend_on_the_bands() {
+        let mut map = Map::parse(include_str!("../assets/maps/default.map")).unwrap();
+        map.bake_lights();
+        map.animate_lights(1.25);
+        let textures = crate::load_textures(map.fog()).unwrap();
+        let trig = Trig::new();
+
+        for &pipeline in &[Pipeline::TrueColor, Pipeline::Indexed] {
+            let settings = |threads| RenderSettings {
+                math: Math::Fixed,
+                pipeline,
+                threads,
+                ..RenderSettings::default()
+            };
+            let one_band = fixed_frames(&mut map, &textures, &settings(1), &trig);
+            for &threads in &[2, 3, 7, 16] {
+                assert_eq!(
+                    fixed_frames(&mut map, &textures, &settings(threads), &trig),
+                    one_band,
+                    "{:?} with {} bands",
+                    pipeline,
+                    threads
+                );
+            }
+        }
+    }
 }
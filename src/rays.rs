@@ -0,0 +1,136 @@
+use crate::fixed::{self, Trig, ANGLES};
+use crate::settings::{Math, Spacing};
+
+// Past these the view is either a keyhole or the rays go behind the camera
+const MIN_FOV: f32 = 1.0;
+const MAX_FOV: f32 = 170.0;
+
+// Everything about a column's ray that doesn't depend on where the camera is
+// or where it's looking, only on which column it is
+pub struct Column {
+    // Angle from the middle of the view, in degrees and in trig table steps
+    pub degrees: f32,
+    pub steps: i32,
+    // How much shorter the straight distance to a wall is than the ray, to undo fisheye
+    pub cos: f32,
+    // How much longer the ray is than the straight distance
+    pub secant: f32,
+    // How far sideways the ray goes for every unit it goes straight ahead, so its
+    // direction is just the view direction plus this much of the one across it
+    pub tan: f32,
+    // secant and tan in fixed point, for Math::Fixed
+    pub secant_fixed: i32,
+    pub tan_fixed: i32,
+}
+
+// The rays of every column on the screen. Worked out once, and again only when
+// the screen width, field of view, spacing or math changes.
+pub struct RayTable {
+    width: i32,
+    fov: f32,
+    spacing: Spacing,
+    math: Math,
+    distance_to_plane: f32,
+    columns: Vec<Column>,
+}
+
+impl RayTable {
+    pub fn new(width: i32, fov: f32, spacing: Spacing, math: Math, trig: &Trig) -> RayTable {
+        // Kept as asked for, so update() can tell when it changes
+        let requested_fov = fov;
+        let fov = fov.max(MIN_FOV).min(MAX_FOV);
+
+        // using the formula tan(angle) = opposite / adjacent
+        // We know the angle, because that's FOV/2
+        // We know opposite, because that's projection's plane width / 2
+        let half_width = width / 2;
+        let fov_steps = fixed::angle(fov);
+        let half_plane = (fov / 2.0).to_radians().tan();
+        let half_plane_fixed = trig.tan(fov_steps / 2);
+        let distance_to_plane = match math {
+            Math::Float => half_width as f32 / half_plane,
+            Math::Fixed => fixed::to_f32(fixed::div(half_width * fixed::ONE, half_plane_fixed)),
+        };
+
+        let columns = (0..width)
+            .map(|x| match math {
+                Math::Float => {
+                    let degrees = match spacing {
+                        // The angle increment between rays is known by the fov. ie, how many
+                        // steps would you need to fit the plane.
+                        Spacing::Even => -fov / 2.0 + fov / width as f32 * x as f32,
+                        // The ray goes through the column's spot on the camera plane, which
+                        // is as wide as tan(fov / 2) on each side of the view direction
+                        Spacing::Perspective => {
+                            (plane_x(x, width) * half_plane).atan().to_degrees()
+                        }
+                    };
+                    let (cos, tan) = (degrees.to_radians().cos(), degrees.to_radians().tan());
+                    Column {
+                        degrees,
+                        steps: fixed::angle(degrees),
+                        cos,
+                        secant: 1.0 / cos,
+                        tan,
+                        secant_fixed: fixed::from_f32(1.0 / cos),
+                        tan_fixed: fixed::from_f32(tan),
+                    }
+                }
+                Math::Fixed => {
+                    let steps = match spacing {
+                        Spacing::Even => {
+                            (x as i64 * fov_steps as i64 / width as i64) as i32 - fov_steps / 2
+                        }
+                        Spacing::Perspective => trig.atan(fixed::mul(
+                            fixed::div((2 * x - width) * fixed::ONE, width * fixed::ONE),
+                            half_plane_fixed,
+                        )),
+                    };
+                    let secant = fixed::div(fixed::ONE, trig.cos(steps));
+                    Column {
+                        degrees: steps as f32 * 360.0 / ANGLES as f32,
+                        steps,
+                        cos: fixed::to_f32(trig.cos(steps)),
+                        secant: fixed::to_f32(secant),
+                        tan: fixed::to_f32(trig.tan(steps)),
+                        secant_fixed: secant,
+                        tan_fixed: trig.tan(steps),
+                    }
+                }
+            })
+            .collect();
+
+        RayTable {
+            width,
+            fov: requested_fov,
+            spacing,
+            math,
+            distance_to_plane,
+            columns,
+        }
+    }
+
+    // Works the table out again if any of what it depends on changed
+    pub fn update(&mut self, width: i32, fov: f32, spacing: Spacing, math: Math, trig: &Trig) {
+        if (self.width, self.fov, self.spacing, self.math) != (width, fov, spacing, math) {
+            *self = RayTable::new(width, fov, spacing, math, trig);
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn distance_to_plane(&self) -> f32 {
+        self.distance_to_plane
+    }
+
+    pub fn column(&self, x: i32) -> &Column {
+        &self.columns[x as usize]
+    }
+}
+
+// Where a column is on the camera plane, from -1 on the left edge to 1 on the right
+fn plane_x(x: i32, width: i32) -> f32 {
+    (2 * x - width) as f32 / width as f32
+}
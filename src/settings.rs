@@ -0,0 +1,70 @@
+use crate::dither::DitherMode;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pipeline {
+    // Textures are shaded with per channel multipliers, lights keep their color
+    TrueColor,
+    // Textures are 8-bit palette indices shaded through colormaps, like Doom
+    Indexed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    // Blocky texels, straight from the texture
+    Nearest,
+    // Smooth blend of the four closest texels, only used by the true color pipeline
+    Bilinear,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Math {
+    // Rays are cast with the platform's trig, which can be off by a bit or so between machines
+    Float,
+    // Rays are cast in fixed point with trig tables, so a view always gives the exact same frame
+    // on every platform. That only holds as long as nothing the frame is drawn with, lighting
+    // and fog included, calls into libm, whose sin, cos, exp and hypot can differ in the last
+    // bit between platforms. Plain arithmetic and sqrt are exactly rounded, so they're fine.
+    // The dither noise only depends on the pixel, so it also holds however many threads draw it.
+    Fixed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Spacing {
+    // Every column is the same number of degrees apart, walls bow a bit towards the edges
+    Even,
+    // Columns are the same distance apart on the projection plane, like a real camera,
+    // so straight walls stay straight even with a wide field of view
+    Perspective,
+}
+
+pub struct RenderSettings {
+    pub dither: DitherMode,
+    pub pipeline: Pipeline,
+    // Draw distant walls and floors from smaller copies of their textures, so they don't shimmer
+    pub mipmaps: bool,
+    pub filter: Filter,
+    // How many bands of columns the screen is split into to draw them in parallel,
+    // 0 for as many as there are cores
+    pub threads: usize,
+    pub math: Math,
+    // How the columns' rays are spread over the field of view
+    pub spacing: Spacing,
+    // Show what mirrors and shiny floors reflect. Blending the reflections in makes
+    // colors the palette doesn't have, so the indexed pipeline never draws them.
+    pub reflections: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            dither: DitherMode::Noise { seed: 0x5eed },
+            pipeline: Pipeline::TrueColor,
+            mipmaps: true,
+            filter: Filter::Nearest,
+            threads: 0,
+            math: Math::Float,
+            spacing: Spacing::Perspective,
+            reflections: true,
+        }
+    }
+}
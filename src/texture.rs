@@ -1,34 +1,293 @@
-use png;
+use png::{BitDepth, ColorType, Transformations};
+use std::fmt;
 use std::fs::File;
+use std::io;
+
+use crate::band::Band;
+use crate::fog::Fog;
+use crate::palette::{Colormap, Palette};
+
+// Darkens the bottom of a wall strip, fading in toward the floor it stands on
+#[derive(Debug, Clone, Copy)]
+pub struct BaseShade {
+    // How much of the strip, from the bottom up, gets darker
+    pub fraction: f32,
+    // How much light is left right at the bottom, from 0 to 1
+    pub occlusion: f32,
+}
+
+impl BaseShade {
+    // How much light is left `up` the strip, from 0 at the bottom to 1 at the top
+    pub fn at(&self, up: f32) -> f32 {
+        let t = (1.0 - up / self.fraction).max(0.0);
+        1.0 - t * (1.0 - self.occlusion)
+    }
+}
+
+// The multiplier for one row of a strip, with its base shading applied
+fn row_mult(
+    mult: Option<&[f32; 3]>,
+    base: Option<BaseShade>,
+    y: i32,
+    height: i32,
+) -> Option<[f32; 3]> {
+    match (mult, base) {
+        (mult, None) => mult.copied(),
+        (mult, Some(base)) => {
+            let shade = base.at(1.0 - (y as f32 + 0.5) / height as f32);
+            let [r, g, b] = mult.copied().unwrap_or([1.0; 3]);
+            Some([r * shade, g * shade, b * shade])
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TextureError {
+    Io(io::Error),
+    Decode(png::DecodingError),
+    Encode(png::EncodingError),
+    // Something the decoder read fine but we still can't turn into RGBA
+    Unsupported(String),
+}
+
+impl fmt::Display for TextureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TextureError::Io(e) => write!(f, "couldn't open texture: {}", e),
+            TextureError::Decode(e) => write!(f, "couldn't decode texture: {}", e),
+            TextureError::Encode(e) => write!(f, "couldn't encode texture: {}", e),
+            TextureError::Unsupported(what) => write!(f, "unsupported texture: {}", what),
+        }
+    }
+}
+
+impl From<io::Error> for TextureError {
+    fn from(e: io::Error) -> TextureError {
+        TextureError::Io(e)
+    }
+}
+
+impl From<png::DecodingError> for TextureError {
+    fn from(e: png::DecodingError) -> TextureError {
+        TextureError::Decode(e)
+    }
+}
+
+impl From<png::EncodingError> for TextureError {
+    fn from(e: png::EncodingError) -> TextureError {
+        TextureError::Encode(e)
+    }
+}
 
 pub struct Texture {
+    // Always 8-bit RGBA, whatever the PNG was stored as
     data: Vec<u8>,
     width: u32,
     height: u32,
+    // Palette index of every texel, for the indexed pipeline
+    indices: Vec<u8>,
+    // Smaller copies of the texture, each half the size of the one before,
+    // for drawing things far enough away that texels are smaller than pixels
+    mips: Vec<Texture>,
 }
 
 impl Texture {
-    pub fn new(path: &str) -> Texture {
-        let decoder = png::Decoder::new(File::open(path).unwrap());
-        let (info, mut reader) = decoder.read_info().unwrap();
+    pub fn new(path: &str) -> Result<Texture, TextureError> {
+        let mut decoder = png::Decoder::new(File::open(path)?);
+        // Palettes, tRNS chunks and low bit depths get expanded to plain 8-bit
+        // samples by the decoder, so we only have to deal with channel counts
+        decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+        let (info, mut reader) = decoder.read_info()?;
         let mut buf = vec![0; info.buffer_size()];
-        reader.next_frame(&mut buf).unwrap();
+        reader.next_frame(&mut buf)?;
 
-        println!("{:?}", info);
-        Texture {
-            data: buf,
+        let (color_type, bit_depth) = reader.output_color_type();
+        if bit_depth != BitDepth::Eight {
+            return Err(TextureError::Unsupported(format!(
+                "{} is {:?} bits per sample after expanding",
+                path, bit_depth
+            )));
+        }
+
+        let data = to_rgba(&buf, color_type)
+            .ok_or_else(|| TextureError::Unsupported(format!("{} is {:?}", path, color_type)))?;
+
+        let mut texture = Texture {
+            data,
             width: info.width,
             height: info.height,
+            indices: Vec::new(),
+            mips: Vec::new(),
+        };
+        texture.generate_mipmaps();
+
+        Ok(texture)
+    }
+
+    // Swaps in new pixels of the same size. Palette indices are dropped, so the
+    // texture has to be indexed again to be drawn by the indexed pipeline.
+    pub fn update(&mut self, data: &[u8]) -> Result<(), TextureError> {
+        if data.len() != self.data.len() {
+            return Err(TextureError::Unsupported(format!(
+                "{} bytes of RGBA for {}x{}",
+                data.len(),
+                self.width,
+                self.height
+            )));
+        }
+
+        self.data.copy_from_slice(data);
+        self.indices.clear();
+        self.generate_mipmaps();
+        Ok(())
+    }
+
+    // A fully transparent texture to draw other ones into
+    pub fn blank(width: u32, height: u32) -> Texture {
+        Texture {
+            data: vec![0; (width * height * 4) as usize],
+            width,
+            height,
+            indices: Vec::new(),
+            mips: Vec::new(),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), TextureError> {
+        let mut encoder = png::Encoder::new(File::create(path)?, self.width, self.height);
+        encoder.set_color(ColorType::RGBA);
+        encoder.set_depth(BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.data)?;
+        Ok(())
+    }
+
+    // Copies a rectangle out into its own texture
+    pub fn region(&self, x: u32, y: u32, width: u32, height: u32) -> Texture {
+        let mut region = Texture::blank(width, height);
+        for ry in 0..height {
+            for rx in 0..width {
+                let (r, g, b, a) = self.rgba_at((x + rx) as i32, (y + ry) as i32);
+                region.set_rgba(rx, ry, [r, g, b, a]);
+            }
+        }
+        region
+    }
+
+    // Nearest neighbour scaled copy, good enough for pixel art
+    pub fn resized(&self, width: u32, height: u32) -> Texture {
+        let mut resized = Texture::blank(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let sx = x * self.width / width;
+                let sy = y * self.height / height;
+                let (r, g, b, a) = self.rgba_at(sx as i32, sy as i32);
+                resized.set_rgba(x, y, [r, g, b, a]);
+            }
+        }
+        resized
+    }
+
+    // Draws another texture over this one, with its top left corner at (x, y)
+    pub fn blit(&mut self, other: &Texture, x: u32, y: u32) {
+        for oy in 0..other.height {
+            for ox in 0..other.width {
+                let (r, g, b, a) = other.rgba_at(ox as i32, oy as i32);
+                self.set_rgba(x + ox, y + oy, [r, g, b, a]);
+            }
+        }
+    }
+
+    fn set_rgba(&mut self, x: u32, y: u32, rgba: [u8; 4]) {
+        if x >= self.width || y >= self.height {
+            return;
         }
+        let idx = ((self.width * y + x) * 4) as usize;
+        self.data[idx..idx + 4].copy_from_slice(&rgba);
+    }
+
+    // Halves the texture over and over down to a single texel,
+    // averaging every 2x2 block into one
+    pub fn generate_mipmaps(&mut self) {
+        let mut mips: Vec<Texture> = Vec::new();
+        loop {
+            let last = mips.last().unwrap_or(self);
+            if last.width == 1 && last.height == 1 {
+                break;
+            }
+
+            let width = (last.width / 2).max(1);
+            let height = (last.height / 2).max(1);
+            let mut mip = Texture::blank(width, height);
+            for y in 0..height {
+                for x in 0..width {
+                    let mut sum = [0u32; 4];
+                    for (dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let sx = (x * 2 + dx).min(last.width - 1);
+                        let sy = (y * 2 + dy).min(last.height - 1);
+                        let (r, g, b, a) = last.rgba_at(sx as i32, sy as i32);
+                        for (s, c) in sum.iter_mut().zip(&[r, g, b, a]) {
+                            *s += *c as u32;
+                        }
+                    }
+                    let [r, g, b, a] = sum;
+                    mip.set_rgba(
+                        x,
+                        y,
+                        [(r / 4) as u8, (g / 4) as u8, (b / 4) as u8, (a / 4) as u8],
+                    );
+                }
+            }
+            mips.push(mip);
+        }
+
+        self.mips = mips;
+    }
+
+    // The mip level that fits best when `density` texels land on every screen pixel
+    pub fn mip(&self, density: f32) -> &Texture {
+        if density <= 1.0 || self.mips.is_empty() {
+            return self;
+        }
+
+        // floor(log2(density)), without going through libm
+        let level = (31 - (density as u32).leading_zeros()) as usize;
+        let level = level.min(self.mips.len());
+        if level == 0 {
+            self
+        } else {
+            &self.mips[level - 1]
+        }
+    }
+
+    // Matches every texel to its closest palette color, so it can be drawn indexed
+    pub fn index_with(&mut self, palette: &Palette) {
+        for mip in self.mips.iter_mut() {
+            mip.index_with(palette);
+        }
+
+        let mut indices = Vec::with_capacity((self.width * self.height) as usize);
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let (r, g, b) = self.color_at(x, y);
+                indices.push(palette.nearest([r, g, b]));
+            }
+        }
+
+        self.indices = indices;
     }
 
     pub fn color_at(&self, x: i32, y: i32) -> (u8, u8, u8) {
-        let idx = ((self.width as i32 * y + x) * 3) as usize;
+        let (r, g, b, _) = self.rgba_at(x, y);
+        (r, g, b)
+    }
+
+    pub fn rgba_at(&self, x: i32, y: i32) -> (u8, u8, u8, u8) {
+        let idx = ((self.width as i32 * y + x) * 4) as usize;
         let d = &self.data;
-        if idx >= d.len() {
-            return (0, 0, 0);
+        if idx + 3 >= d.len() {
+            return (0, 0, 0, 0);
         }
-        (d[idx], d[idx + 1], d[idx + 2])
+        (d[idx], d[idx + 1], d[idx + 2], d[idx + 3])
     }
 
     pub fn width(&self) -> i32 {
@@ -45,60 +304,348 @@ impl Texture {
         tex_x: i32,
         top: i32,
         bottom: i32,
-        buf: &mut [u8],
+        band: &mut Band,
         mult: Option<&[f32; 3]>,
+        fog: Option<(&Fog, f32)>,
+        base: Option<BaseShade>,
     ) {
         let height = bottom - top;
 
-        // TODO: Replace fixed 320 and 200s by a width being passed
         for y in 0..height {
             let tex_y = (y as f64 / height as f64 * self.height as f64).round() as usize;
 
             let screen_y = (top + y as i32) as usize;
 
-            if screen_y > 200 {
+            if screen_y as i32 >= band.height() {
                 continue;
             }
-            self.copy_to_ex(tex_x, tex_y as i32, x, screen_y as i32, buf, mult)
+            let mult = row_mult(mult, base, y, height);
+            self.copy_to_ex(
+                tex_x,
+                tex_y as i32,
+                x,
+                screen_y as i32,
+                band,
+                mult.as_ref(),
+                fog,
+            )
         }
     }
 
-    pub fn draw_strip_at(&self, x: i32, tex_x: i32, top: i32, bottom: i32, buf: &mut [u8]) {
-        self.draw_strip_at_ex(x, tex_x, top, bottom, buf, None)
+    // Like draw_strip_at_ex, but mixed with what's already on the screen. `opacity`
+    // goes from 0 (nothing of the texture shows) to 1 (same as drawing it normally).
+    pub fn blend_strip(
+        &self,
+        x: i32,
+        tex_x: i32,
+        top: i32,
+        bottom: i32,
+        band: &mut Band,
+        mult: Option<&[f32; 3]>,
+        fog: Option<(&Fog, f32)>,
+        base: Option<BaseShade>,
+        opacity: f32,
+    ) {
+        let height = bottom - top;
+        if height <= 0 {
+            return;
+        }
+
+        let start = (-top).max(0);
+        for y in start..height {
+            let screen_y = top + y;
+            if screen_y >= band.height() {
+                break;
+            }
+
+            let tex_y = (y as i64 * self.height as i64 / height as i64) as i32;
+            let mult = row_mult(mult, base, y, height);
+            self.copy_blended(tex_x, tex_y, x, screen_y, band, mult.as_ref(), fog, opacity);
+        }
     }
-}
 
-impl Drawable for Texture {
-    fn copy_to_ex(
+    // copy_to_ex, mixed with what's already on the screen like blend_strip
+    pub fn copy_blended(
         &self,
         tex_x: i32,
         tex_y: i32,
         x: i32,
         y: i32,
-        buf: &mut [u8],
+        band: &mut Band,
         mult: Option<&[f32; 3]>,
+        fog: Option<(&Fog, f32)>,
+        opacity: f32,
     ) {
-        let (r, g, b) = self.color_at(tex_x, tex_y);
+        let (r, g, b, a) = self.rgba_at(tex_x, tex_y);
+        let a = (a as f32 * opacity.max(0.0).min(1.0)) as u8;
+        put_texel((r, g, b, a), x, y, band, mult, fog);
+    }
+
+    pub fn draw_strip_at(&self, x: i32, tex_x: i32, top: i32, bottom: i32, band: &mut Band) {
+        self.draw_strip_at_ex(x, tex_x, top, bottom, band, None, None, None)
+    }
 
-        let idx = ((320 * y + x) * 4) as usize;
-        if idx >= buf.len() {
+    // Like draw_strip_at_ex but bilinearly filtered, `u` being the 16.16 fixed point
+    // texture column so the strip can blend with its neighbours too
+    pub fn draw_strip_filtered(
+        &self,
+        x: i32,
+        u: i32,
+        top: i32,
+        bottom: i32,
+        band: &mut Band,
+        mult: Option<&[f32; 3]>,
+        fog: Option<(&Fog, f32)>,
+        base: Option<BaseShade>,
+    ) {
+        let height = bottom - top;
+        if height <= 0 {
             return;
         }
 
-        let (r, g, b) = if let Some(&[mr, mg, mb]) = mult {
-            (
-                (r as f32 * mr) as u8,
-                (g as f32 * mg) as u8,
-                (b as f32 * mb) as u8,
-            )
+        let step = ((self.height as i64) << 16) / height as i64;
+
+        // Skip whatever is above the screen, and sample the middle of every pixel
+        let start = (-top).max(0);
+        let mut v = step * start as i64 + step / 2;
+        for y in start..height {
+            let screen_y = top + y;
+            if screen_y >= band.height() {
+                break;
+            }
+
+            let mult = row_mult(mult, base, y, height);
+            let texel = self.sample_bilinear(u, v as i32, false);
+            put_texel(texel, x, screen_y, band, mult.as_ref(), fog);
+            v += step;
+        }
+    }
+
+    pub fn copy_filtered(
+        &self,
+        u: i32,
+        v: i32,
+        x: i32,
+        y: i32,
+        band: &mut Band,
+        mult: Option<&[f32; 3]>,
+        fog: Option<(&Fog, f32)>,
+    ) {
+        put_texel(self.sample_bilinear(u, v, true), x, y, band, mult, fog);
+    }
+
+    // Blends the four texels around a 16.16 fixed point texture coordinate.
+    // Columns always wrap since textures tile sideways, but rows only wrap when
+    // `wrap_v` is set: floors tile both ways, while a wall's top and bottom rows
+    // are clamped so they don't pick up the other end of the texture.
+    pub fn sample_bilinear(&self, u: i32, v: i32, wrap_v: bool) -> (u8, u8, u8, u8) {
+        // Texel centers are half a texel in
+        let u = u - 0x8000;
+        let v = v - 0x8000;
+
+        let (width, height) = (self.width as i32, self.height as i32);
+        let x0 = (u >> 16).rem_euclid(width);
+        let x1 = (x0 + 1) % width;
+        let (y0, y1) = if wrap_v {
+            let y0 = (v >> 16).rem_euclid(height);
+            (y0, (y0 + 1) % height)
         } else {
-            (r, g, b)
+            let clamp = |y: i32| y.max(0).min(height - 1);
+            (clamp(v >> 16), clamp((v >> 16) + 1))
+        };
+
+        // 8 bits of weight each way is plenty for 8-bit channels, and
+        // the four weights always add up to exactly 1 << 16
+        let fx = ((u >> 8) & 0xff) as u32;
+        let fy = ((v >> 8) & 0xff) as u32;
+        let weights = [
+            (256 - fx) * (256 - fy),
+            fx * (256 - fy),
+            (256 - fx) * fy,
+            fx * fy,
+        ];
+        let texels = [
+            self.rgba_at(x0, y0),
+            self.rgba_at(x1, y0),
+            self.rgba_at(x0, y1),
+            self.rgba_at(x1, y1),
+        ];
+
+        let mut sum = [0u32; 4];
+        for ((r, g, b, a), weight) in texels.iter().zip(weights.iter()) {
+            sum[0] += *r as u32 * weight;
+            sum[1] += *g as u32 * weight;
+            sum[2] += *b as u32 * weight;
+            sum[3] += *a as u32 * weight;
+        }
+
+        (
+            (sum[0] >> 16) as u8,
+            (sum[1] >> 16) as u8,
+            (sum[2] >> 16) as u8,
+            (sum[3] >> 16) as u8,
+        )
+    }
+
+    // Like draw_strip_at_ex but for the indexed pipeline, texels are shaded through the
+    // colormap and the texture is stepped in 16.16 fixed point, so there's no float math
+    pub fn draw_strip_indexed(
+        &self,
+        x: i32,
+        tex_x: i32,
+        top: i32,
+        bottom: i32,
+        band: &mut Band,
+        colormap: &Colormap,
+        light_level: usize,
+        fog_level: usize,
+    ) {
+        let height = bottom - top;
+        if height <= 0 {
+            return;
+        }
+
+        let step = ((self.height as i64) << 16) / height as i64;
+
+        // Skip whatever is above the screen
+        let start = (-top).max(0);
+        let mut tex_y = step * start as i64;
+        for y in start..height {
+            let screen_y = top + y;
+            if screen_y >= band.height() {
+                break;
+            }
+
+            self.copy_indexed(
+                tex_x,
+                (tex_y >> 16) as i32,
+                x,
+                screen_y,
+                band,
+                colormap,
+                light_level,
+                fog_level,
+            );
+            tex_y += step;
+        }
+    }
+
+    pub fn copy_indexed(
+        &self,
+        tex_x: i32,
+        tex_y: i32,
+        x: i32,
+        y: i32,
+        band: &mut Band,
+        colormap: &Colormap,
+        light_level: usize,
+        fog_level: usize,
+    ) {
+        let index = match self
+            .indices
+            .get((self.width as i32 * tex_y + tex_x) as usize)
+        {
+            Some(index) => *index,
+            None => return,
         };
 
-        buf[idx..idx + 4].copy_from_slice(&[r, g, b, 0xff]);
+        // The palette has no transparency, so only fully see-through texels are skipped
+        if self.rgba_at(tex_x, tex_y).3 == 0 {
+            return;
+        }
+
+        let pixel = match band.pixel_mut(x, y) {
+            Some(pixel) => pixel,
+            None => return,
+        };
+
+        let [r, g, b] = colormap.shade(index, light_level, fog_level);
+        pixel.copy_from_slice(&[r, g, b, 0xff]);
     }
 }
 
+impl Drawable for Texture {
+    fn copy_to_ex(
+        &self,
+        tex_x: i32,
+        tex_y: i32,
+        x: i32,
+        y: i32,
+        band: &mut Band,
+        mult: Option<&[f32; 3]>,
+        fog: Option<(&Fog, f32)>,
+    ) {
+        put_texel(self.rgba_at(tex_x, tex_y), x, y, band, mult, fog);
+    }
+}
+
+// Shades a texel and writes it to the screen, skipping it if it's see-through
+fn put_texel(
+    (r, g, b, a): (u8, u8, u8, u8),
+    x: i32,
+    y: i32,
+    band: &mut Band,
+    mult: Option<&[f32; 3]>,
+    fog: Option<(&Fog, f32)>,
+) {
+    if a == 0 {
+        return;
+    }
+
+    let pixel = match band.pixel_mut(x, y) {
+        Some(pixel) => pixel,
+        None => return,
+    };
+
+    let (r, g, b) = if let Some(&[mr, mg, mb]) = mult {
+        (
+            (r as f32 * mr) as u8,
+            (g as f32 * mg) as u8,
+            (b as f32 * mb) as u8,
+        )
+    } else {
+        (r, g, b)
+    };
+
+    let (r, g, b) = if let Some((fog, amount)) = fog {
+        fog.apply((r, g, b), amount)
+    } else {
+        (r, g, b)
+    };
+
+    // Blend partly transparent texels over whatever was drawn there before
+    let (r, g, b) = if a < 0xff {
+        let blend = |c: u8, under: u8| {
+            ((c as u32 * a as u32 + under as u32 * (0xff - a) as u32) / 0xff) as u8
+        };
+        (blend(r, pixel[0]), blend(g, pixel[1]), blend(b, pixel[2]))
+    } else {
+        (r, g, b)
+    };
+
+    pixel.copy_from_slice(&[r, g, b, 0xff]);
+}
+
+// Widens 8-bit decoded samples of any channel count into RGBA
+fn to_rgba(buf: &[u8], color_type: ColorType) -> Option<Vec<u8>> {
+    let rgba = match color_type {
+        ColorType::RGBA => buf.to_vec(),
+        ColorType::RGB => buf
+            .chunks_exact(3)
+            .flat_map(|p| vec![p[0], p[1], p[2], 0xff])
+            .collect(),
+        ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|p| vec![p[0], p[0], p[0], p[1]])
+            .collect(),
+        ColorType::Grayscale => buf.iter().flat_map(|&v| vec![v, v, v, 0xff]).collect(),
+        // EXPAND should have turned these into RGB(A) already
+        ColorType::Indexed => return None,
+    };
+
+    Some(rgba)
+}
+
 pub trait Drawable {
     fn copy_to_ex(
         &self,
@@ -106,11 +653,12 @@ pub trait Drawable {
         tex_y: i32,
         x: i32,
         y: i32,
-        buf: &mut [u8],
+        band: &mut Band,
         mult: Option<&[f32; 3]>,
+        fog: Option<(&Fog, f32)>,
     );
 
-    fn copy_to(&self, tex_x: i32, tex_y: i32, x: i32, y: i32, buf: &mut [u8]) {
-        self.copy_to_ex(tex_x, tex_y, x, y, buf, None)
+    fn copy_to(&self, tex_x: i32, tex_y: i32, x: i32, y: i32, band: &mut Band) {
+        self.copy_to_ex(tex_x, tex_y, x, y, band, None, None)
     }
 }
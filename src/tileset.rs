@@ -0,0 +1,110 @@
+use crate::texture::{Texture, TextureError};
+
+// A bunch of same-sized textures that came out of one image, addressed by ID.
+// IDs go left to right, top to bottom, like the tiles in the image.
+pub struct Tileset {
+    tiles: Vec<Texture>,
+}
+
+impl Tileset {
+    // Loads an image and slices it into tiles of the given size,
+    // ignoring whatever is left over on the right and bottom edges
+    pub fn slice(path: &str, tile_width: u32, tile_height: u32) -> Result<Tileset, TextureError> {
+        Tileset::from_atlas(&Texture::new(path)?, tile_width, tile_height)
+    }
+
+    pub fn from_atlas(
+        atlas: &Texture,
+        tile_width: u32,
+        tile_height: u32,
+    ) -> Result<Tileset, TextureError> {
+        if tile_width == 0 || tile_height == 0 {
+            return Err(TextureError::Unsupported(format!(
+                "tiles of {}x{}",
+                tile_width, tile_height
+            )));
+        }
+
+        let columns = atlas.width() as u32 / tile_width;
+        let rows = atlas.height() as u32 / tile_height;
+        if columns == 0 || rows == 0 {
+            return Err(TextureError::Unsupported(format!(
+                "a {}x{} image can't fit a {}x{} tile",
+                atlas.width(),
+                atlas.height(),
+                tile_width,
+                tile_height
+            )));
+        }
+
+        let mut tiles = Vec::with_capacity((columns * rows) as usize);
+        for row in 0..rows {
+            for column in 0..columns {
+                let mut tile = atlas.region(
+                    column * tile_width,
+                    row * tile_height,
+                    tile_width,
+                    tile_height,
+                );
+                tile.generate_mipmaps();
+                tiles.push(tile);
+            }
+        }
+
+        Ok(Tileset { tiles })
+    }
+
+    // Builds one atlas image out of separate files, `columns` tiles wide, in the order given.
+    // Anything that isn't tile sized already gets scaled to fit.
+    pub fn pack(
+        paths: &[&str],
+        tile_width: u32,
+        tile_height: u32,
+        columns: u32,
+    ) -> Result<Texture, TextureError> {
+        let columns = columns.max(1).min(paths.len().max(1) as u32);
+        let rows = (paths.len() as u32 + columns - 1) / columns;
+        let mut atlas = Texture::blank(columns * tile_width, rows.max(1) * tile_height);
+
+        for (i, path) in paths.iter().enumerate() {
+            let texture = Texture::new(path)?;
+            let tile =
+                if texture.width() as u32 == tile_width && texture.height() as u32 == tile_height {
+                    texture
+                } else {
+                    texture.resized(tile_width, tile_height)
+                };
+
+            let i = i as u32;
+            atlas.blit(
+                &tile,
+                (i % columns) * tile_width,
+                (i / columns) * tile_height,
+            );
+        }
+
+        Ok(atlas)
+    }
+
+    // The tile with the given ID, or the first one if there's no such tile
+    pub fn get(&self, id: usize) -> &Texture {
+        self.tiles.get(id).unwrap_or(&self.tiles[0])
+    }
+
+    // Puts a texture in a slot, so a wall using that ID draws it. Slots past the
+    // end get filled up with blank tiles.
+    pub fn bind(&mut self, id: usize, texture: Texture) {
+        while self.tiles.len() <= id {
+            self.tiles.push(Texture::blank(1, 1));
+        }
+        self.tiles[id] = texture;
+    }
+
+    pub fn tiles(&self) -> &[Texture] {
+        &self.tiles
+    }
+
+    pub fn tiles_mut(&mut self) -> &mut [Texture] {
+        &mut self.tiles
+    }
+}